@@ -0,0 +1,74 @@
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+use crate::backend::{BackendError, BackendHealth, Sent, ShockerBackend, ShockerCommand, ShockerInfo};
+use tokio::time::Duration;
+
+// Dry run backend, logs what would be sent instead of touching any hardware
+// Handy for tuning touchpoints without wearing anything
+pub struct MockBackend {
+    sent: Mutex<Vec<ShockerCommand>>,
+}
+
+impl MockBackend {
+    pub fn new() -> MockBackend {
+        MockBackend {
+            sent: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl ShockerBackend for MockBackend {
+    fn name(&self) -> &'static str {
+        "Mock"
+    }
+
+    async fn send_control(&self, commands: &[ShockerCommand]) -> Result<Vec<Sent>, BackendError> {
+        let mut sent = self.sent.lock().await;
+        for command in commands {
+            log::info!("[Mock] shocker {} method {} intensity {:.2} for {} ms", command.id, command.method, command.intensity, command.duration);
+            sent.push(command.clone());
+        }
+        Ok(commands.iter().map(|command| Sent::new(&command.id, command.method, Duration::from_millis(command.duration))).collect())
+    }
+
+    // Takes every ID, as asked
+    fn hardware_duration(&self, command: &ShockerCommand) -> Option<Duration> {
+        Some(Duration::from_millis(command.duration))
+    }
+
+    async fn stop_all(&self) -> Result<(), BackendError> {
+        log::info!("[Mock] stop all shockers");
+        Ok(())
+    }
+
+    async fn stop(&self, ids: &[String]) -> Result<(), BackendError> {
+        log::info!("[Mock] stop shockers {:?}", ids);
+        Ok(())
+    }
+
+    async fn list_shockers(&self) -> Result<Vec<ShockerInfo>, BackendError> {
+        // Every shocker the mock has been asked to drive
+        let sent = self.sent.lock().await;
+        let mut shockers: Vec<ShockerInfo> = Vec::new();
+        for command in sent.iter() {
+            if !shockers.iter().any(|shocker| shocker.id == command.id) {
+                shockers.push(ShockerInfo {
+                    id: command.id.clone(),
+                    name: format!("Mock {}", command.id),
+                    paused: false,
+                });
+            }
+        }
+        Ok(shockers)
+    }
+
+    async fn health(&self) -> BackendHealth {
+        BackendHealth::Connected
+    }
+
+    async fn close(&self) -> Result<(), BackendError> {
+        log::info!("[Mock] closed");
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use async_trait::async_trait;
+use std::fmt;
+use std::sync::Arc;
+use tokio::time::Duration;
+use crate::config;
+use crate::osc::touchpoints::CommandState;
+use crate::{openshock_legacy,openshock,pishock};
+
+pub mod mock;
+pub mod router;
+#[cfg(test)]
+pub mod test_server;
+
+// Methods as used in touchpoints.toml and the command map keys
+pub const METHOD_SHOCK: u8 = 1;
+pub const METHOD_VIBRATE: u8 = 2;
+pub const METHOD_SOUND: u8 = 3;
+
+// A single control frame for one shocker, built by the router from an active CommandState
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShockerCommand {
+    pub id: String,
+    pub method: u8,
+    pub intensity: f32, // 0.0 - 1.0, backends scale this to whatever their API expects
+    pub duration: u64,  // milliseconds
+}
+
+impl ShockerCommand {
+    // Command map keys are "{id}_{method}", the method is always the last segment
+    pub fn from_command_state(key: &str, command_state: &CommandState) -> Option<ShockerCommand> {
+        let (id, method) = key.rsplit_once('_')?;
+        let method = method.parse::<u8>().ok()?;
+        Some(ShockerCommand {
+            id: id.to_string(),
+            method,
+            intensity: command_state.intensity,
+            duration: command_state.duration,
+        })
+    }
+}
+
+// What a backend put on the hardware for one shocker, after its own clamping, rounding and dedup
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sent {
+    pub id: String,
+    pub method: u8,
+    pub duration: Duration,
+}
+
+impl Sent {
+    pub fn new(id: &str, method: u8, duration: Duration) -> Sent {
+        Sent { id: id.to_string(), method, duration }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ShockerInfo {
+    pub id: String,
+    pub name: String,
+    pub paused: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackendHealth {
+    Connected,
+    Disconnected(String),
+}
+
+#[derive(Debug)]
+pub enum BackendError {
+    Connection(String),
+    Unauthorized(String),
+    RateLimited(Option<Duration>),
+    Rejected(String),
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::Connection(e) => write!(f, "connection error: {}", e),
+            BackendError::Unauthorized(e) => write!(f, "not authorized: {}", e),
+            BackendError::RateLimited(Some(retry_after)) => write!(f, "rate limited, retry after {:?}", retry_after),
+            BackendError::RateLimited(None) => write!(f, "rate limited"),
+            BackendError::Rejected(e) => write!(f, "request rejected: {}", e),
+            BackendError::Unsupported(e) => write!(f, "unsupported: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+// Everything the router needs from a firmware/API implementation
+// Backends receive every active command and skip IDs that aren't theirs, so several can run side by side
+#[async_trait]
+pub trait ShockerBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Called every router pass with everything active, an empty slice means nothing is, so running shockers should stop
+    // Returns what was actually sent this pass, the safety limiter charges shock time from it
+    async fn send_control(&self, commands: &[ShockerCommand]) -> Result<Vec<Sent>, BackendError>;
+
+    // How long the hardware would run this command, None if the ID isn't ours
+    fn hardware_duration(&self, command: &ShockerCommand) -> Option<Duration>;
+
+    async fn stop_all(&self) -> Result<(), BackendError>;
+
+    // Stop just these shockers right away, EX: their touchpoints are gone after an avatar change
+    // IDs that aren't ours are skipped
+    async fn stop(&self, ids: &[String]) -> Result<(), BackendError>;
+
+    async fn list_shockers(&self) -> Result<Vec<ShockerInfo>, BackendError>;
+
+    async fn health(&self) -> BackendHealth;
+
+    // Release connections on shutdown, after stop_all, anything still queued has to go out first
+    async fn close(&self) -> Result<(), BackendError>;
+}
+
+// Build every backend listed in firmware.firmware (comma separated, EX: "legacy, openshock")
+// New backends only need an entry in create_backend
+pub async fn build_backends() -> Vec<Arc<dyn ShockerBackend>> {
+    let firmware_config = &config::get_config().firmware;
+    let mut backends: Vec<Arc<dyn ShockerBackend>> = Vec::new();
+
+    for name in firmware_config.firmware.split(',').map(|name| name.trim().to_ascii_lowercase()) {
+        if name.is_empty() {
+            continue;
+        }
+        match create_backend(&name).await {
+            Ok(backend) => {
+                log::info!("{} backend is enabled.", backend.name());
+                backends.push(backend);
+            },
+            Err(e) => log::error!("Failed to start {} backend: {}", name, e),
+        }
+    }
+
+    if backends.is_empty() {
+        log::warn!("No firmware backends are running, commands will not reach any shockers.");
+    }
+    backends
+}
+
+async fn create_backend(name: &str) -> Result<Arc<dyn ShockerBackend>, BackendError> {
+    let firmware_config = &config::get_config().firmware;
+
+    match name {
+        "legacy" => {
+            let backend = openshock_legacy::handler::LegacyBackend::connect(&firmware_config.api_endpoint).await?;
+            Ok(Arc::new(backend))
+        },
+        "openshock" => {
+            let backend = openshock::handler::OpenShockBackend::new(&firmware_config.api_endpoint, &firmware_config.api_authtoken)?;
+            Ok(Arc::new(backend))
+        },
+        "openshock_live" => {
+            let backend = openshock::handler::OpenShockLiveBackend::new(&firmware_config.api_endpoint, &firmware_config.api_authtoken)?;
+            Ok(Arc::new(backend))
+        },
+        "pishock" => Ok(Arc::new(pishock::handler::PiShockBackend::new()?)),
+        "mock" => Ok(Arc::new(mock::MockBackend::new())),
+        _ => Err(BackendError::Unsupported("unknown firmware, options are legacy, openshock, openshock_live, pishock, mock")),
+    }
+}
@@ -0,0 +1,144 @@
+use crate::backend::{BackendError, Sent, ShockerBackend, ShockerCommand};
+use crate::osc::feedback;
+use crate::osc::touchpoints::CommandState;
+use crate::safety::estop;
+use crate::safety::limiter::Limiter;
+use crate::shutdown::Shutdown;
+use futures::future::join_all;
+use once_cell::sync::Lazy;
+use tokio::sync::Mutex;
+use std::sync::Arc;
+use tokio::time::{Instant,self,Duration};
+use std::collections::{HashMap, HashSet};
+
+// How often active commands are pushed to the backends
+const DISPATCH_INTERVAL: Duration = Duration::from_millis(150);
+
+// Shocker IDs to stop on the next pass, zeroing their commands only stops resending what the hardware is already running
+static STOP_REQUESTS: Lazy<std::sync::Mutex<HashSet<String>>> = Lazy::new(|| std::sync::Mutex::new(HashSet::new()));
+
+// Ask every backend to stop these shockers before the next dispatch, anything still wanted is sent again after
+pub fn request_stop(ids: impl IntoIterator<Item = String>) {
+    STOP_REQUESTS.lock().expect("Stop requests lock poisoned").extend(ids);
+}
+
+fn take_stop_requests() -> Vec<String> {
+    let mut ids: Vec<String> = STOP_REQUESTS.lock().expect("Stop requests lock poisoned").drain().collect();
+    ids.sort();
+    ids
+}
+
+// Drive every configured backend from the command map
+// On shutdown the current batch is finished before returning, so no backend is cut off mid-send
+pub async fn run(backends: Vec<Arc<dyn ShockerBackend>>, commandmap: Arc<Mutex<HashMap<String, CommandState>>>, shutdown: Shutdown) {
+    log::debug!("Backend Router: driving {} backend(s)", backends.len());
+
+    // Whether the current emergency stop has already been sent to the backends
+    let mut estop_handled = false;
+    let mut limiter = Limiter::new();
+
+    loop {
+        if estop::is_engaged() {
+            // stop_all covers them
+            take_stop_requests();
+            if !estop_handled {
+                estop::zero_commands(&commandmap).await;
+                // A backend that failed to stop gets another try next pass
+                estop_handled = stop_all(&backends).await;
+            }
+            limiter.idle(Instant::now());
+            feedback::record_dispatch(&[]);
+        } else {
+            estop_handled = false;
+            stop_requested(&backends).await;
+            dispatch(&backends, &commandmap, &mut limiter).await;
+        }
+
+        // Sleep until the next loop iteration to maintain the loop interval
+        tokio::select! {
+            _ = time::sleep(DISPATCH_INTERVAL) => {},
+            // Stop right away instead of finishing the sleep
+            _ = estop::engaged() => {},
+            _ = shutdown.wait_shutdown_triggered() => {
+                log::debug!("Backend Router: stopped");
+                return;
+            },
+        }
+    }
+}
+
+async fn dispatch(backends: &[Arc<dyn ShockerBackend>], commandmap: &Arc<Mutex<HashMap<String, CommandState>>>, limiter: &mut Limiter) {
+    let now = Instant::now();
+    // Every command goes through the safety limiter, even when there is nothing to send it still has to see the pass
+    let commands = limiter.apply(active_commands(commandmap, now).await, now, |command| hardware_duration(backends, command));
+    feedback::record_dispatch(&commands);
+
+    if !commands.is_empty() {
+        log::debug!("Dispatching {} command(s)", commands.len());
+    }
+    // Backends get every pass, even an empty one, so they can stop whatever was released since the last
+    // Send to all backends at once so a slow API doesn't hold up the others
+    let results = join_all(backends.iter().map(|backend| backend.send_control(&commands))).await;
+    let mut sent: Vec<Sent> = Vec::new();
+    for (backend, result) in backends.iter().zip(results) {
+        match result {
+            Ok(backend_sent) => sent.extend(backend_sent),
+            Err(BackendError::Unsupported(e)) => log::debug!("{} backend: {}", backend.name(), e),
+            Err(e) => log::error!("{} backend failed to send control: {}", backend.name(), e),
+        }
+    }
+    limiter.record(&sent, now);
+}
+
+// The longest any backend would run the command, the command's own duration if no backend takes it
+fn hardware_duration(backends: &[Arc<dyn ShockerBackend>], command: &ShockerCommand) -> Duration {
+    backends.iter()
+        .filter_map(|backend| backend.hardware_duration(command))
+        .max()
+        .unwrap_or(Duration::from_millis(command.duration))
+}
+
+async fn stop_requested(backends: &[Arc<dyn ShockerBackend>]) {
+    let ids = take_stop_requests();
+    if ids.is_empty() {
+        return;
+    }
+    let results = join_all(backends.iter().map(|backend| backend.stop(&ids))).await;
+    for (backend, result) in backends.iter().zip(results) {
+        match result {
+            Ok(()) => log::debug!("{} backend stopped {:?}", backend.name(), ids),
+            Err(e) => log::error!("{} backend failed to stop {:?}: {}", backend.name(), ids, e),
+        }
+    }
+}
+
+// Returns true if every backend confirmed the stop
+async fn stop_all(backends: &[Arc<dyn ShockerBackend>]) -> bool {
+    let results = join_all(backends.iter().map(|backend| backend.stop_all())).await;
+    let mut all_stopped = true;
+    for (backend, result) in backends.iter().zip(results) {
+        match result {
+            Ok(()) => log::warn!("{} backend stopped all shockers", backend.name()),
+            Err(e) => {
+                log::error!("{} backend failed to stop all shockers: {}", backend.name(), e);
+                all_stopped = false;
+            },
+        }
+    }
+    all_stopped
+}
+
+// Snapshot the non-expired, non-zero commands without holding the lock while backends send
+async fn active_commands(commandmap: &Arc<Mutex<HashMap<String, CommandState>>>, now: Instant) -> Vec<ShockerCommand> {
+    let commandmap_lock = commandmap.lock().await;
+    commandmap_lock.iter()
+        .filter(|(_, command_state)| command_state.expiry > now && command_state.intensity > 0.0)
+        .filter_map(|(key, command_state)| {
+            let command = ShockerCommand::from_command_state(key, command_state);
+            if command.is_none() {
+                log::error!("Invalid command map key: {}", key);
+            }
+            command
+        })
+        .collect()
+}
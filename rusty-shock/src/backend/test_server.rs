@@ -0,0 +1,93 @@
+// Bare bones HTTP server for backend tests, answers every request the same way and hands what it got to the test
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration};
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    // Header names are lowercased
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+pub struct TestServer {
+    pub addr: SocketAddr,
+    requests: mpsc::UnboundedReceiver<Request>,
+}
+
+impl TestServer {
+    pub async fn start(status: u16, body: &'static str) -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, requests) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    // Keep alive, so read requests until the client hangs up
+                    while let Some(request) = read_request(&mut stream).await {
+                        let _ = sender.send(request);
+                        let response = format!("HTTP/1.1 {} Test\r\ncontent-length: {}\r\n\r\n{}", status, body.len(), body);
+                        if stream.get_mut().write_all(response.as_bytes()).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        TestServer { addr, requests }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    // The next request, panics if nothing shows up within a couple of seconds
+    pub async fn request(&mut self) -> Request {
+        time::timeout(Duration::from_secs(2), self.requests.recv()).await
+            .expect("no request reached the test server")
+            .expect("test server stopped")
+    }
+
+    // Nothing else was sent
+    pub fn assert_idle(&mut self) {
+        if let Ok(request) = self.requests.try_recv() {
+            panic!("unexpected request: {:?}", request);
+        }
+    }
+}
+
+async fn read_request<S: tokio::io::AsyncRead + Unpin>(stream: &mut BufReader<S>) -> Option<Request> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await.ok()? == 0 {
+        return None;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+    }
+
+    let length = headers.get("content-length").and_then(|length| length.parse::<usize>().ok()).unwrap_or(0);
+    let mut body = vec![0u8; length];
+    stream.read_exact(&mut body).await.ok()?;
+    Some(Request { method, path, headers, body: String::from_utf8_lossy(&body).into_owned() })
+}
@@ -1,6 +1,6 @@
 use once_cell::sync::Lazy;
 use serde::Deserialize;
-use std::{fs,io,io::Write,str::FromStr};
+use std::{collections::HashMap,fs,io,io::Write,str::FromStr};
 use swing::Config as LoggerConfig;
 use log::LevelFilter;
 
@@ -12,6 +12,21 @@ pub struct Config {
     pub logging: Logging,
     pub firmware: Firmware,
     pub features: Features,
+    // Optional sections, older config files without them keep working
+    #[serde(default)]
+    pub pishock: PiShock,
+    #[serde(default)]
+    pub world_command: WorldCommand,
+    #[serde(default)]
+    pub emergency_stop: EmergencyStop,
+    #[serde(default)]
+    pub safety: Safety,
+    #[serde(default)]
+    pub feedback: Feedback,
+    #[serde(default)]
+    pub oscquery: OscQuery,
+    #[serde(default)]
+    pub relay: Relay,
 }
 
 // Expected OSC config, listen_port,send_port,ip_address
@@ -20,6 +35,32 @@ pub struct Osc {
     pub listen_port: u16,
     pub send_port: u16,
     pub ip_address: String,
+    // Interface the OSC server listens on, blank means ip_address
+    #[serde(default)]
+    pub bind_address: String,
+    // IPs and CIDR ranges allowed to send us OSC, empty allows everyone
+    #[serde(default)]
+    pub allowed_sources: Vec<String>,
+    // Non loopback senders have to start every address with this, blank turns it off
+    #[serde(default)]
+    pub secret_prefix: String,
+    // 0 turns OSC over TCP off
+    #[serde(default)]
+    pub tcp_port: u16,
+    // slip (OSC 1.1) or length (OSC 1.0)
+    #[serde(default = "default_tcp_framing")]
+    pub tcp_framing: String,
+    // Touchpoint addresses without a leading / are relative to this
+    #[serde(rename = "touchpointPrefix", default = "default_touchpoint_prefix")]
+    pub touchpoint_prefix: String,
+}
+
+fn default_touchpoint_prefix() -> String {
+    "/avatar/parameters/".to_string()
+}
+
+fn default_tcp_framing() -> String {
+    "slip".to_string()
 }
 
 #[derive(Deserialize)]
@@ -39,6 +80,157 @@ pub struct Features {
     pub disabled_features : Vec<String>,
 }
 
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct WorldCommand {
+    // Blank means auto detect
+    pub log_directory: String,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct EmergencyStop {
+    pub osc_address: String,
+    pub rearm_osc_address: String,
+    // 0 disables the local control endpoint
+    pub control_port: u16,
+    // When set, control requests have to carry it in X-ShockRS-Token
+    pub control_token: String,
+}
+
+impl Default for EmergencyStop {
+    fn default() -> Self {
+        EmergencyStop {
+            osc_address: "/avatar/parameters/ShockRS_EStop".to_string(),
+            rearm_osc_address: String::new(),
+            control_port: 9150,
+            control_token: String::new(),
+        }
+    }
+}
+
+// Limits applied to every command right before it reaches a backend, on top of the touchpoint limits
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Safety {
+    pub max_shock_intensity: f32,
+    pub max_vibrate_intensity: f32,
+    // Cumulative shock time across all shockers, 0 disables
+    pub shock_seconds_per_minute: f32,
+    pub shock_seconds_per_session: f32,
+    // Minimum time between the end of one shock and the start of the next on the same shocker
+    pub min_shock_gap_ms: u64,
+    // Held contacts (ramps, grabbed PhysBones) are let go after this long, 0 disables
+    pub max_hold_ms: u64,
+    // ...or after this long without a new value for them, 0 disables
+    pub stale_hold_ms: u64,
+}
+
+impl Default for Safety {
+    fn default() -> Self {
+        Safety {
+            max_shock_intensity: 1.0,
+            max_vibrate_intensity: 1.0,
+            shock_seconds_per_minute: 30.0,
+            shock_seconds_per_session: 0.0,
+            min_shock_gap_ms: 0,
+            max_hold_ms: 30000,
+            stale_hold_ms: 10000,
+        }
+    }
+}
+
+// Avatar parameters sent back to osc.ip_address:osc.send_port, a blank address turns that one off
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Feedback {
+    pub enabled: bool,
+    pub interval_ms: u64,
+    pub connected_parameter: String,
+    pub armed_parameter: String,
+    pub intensity_parameter: String,
+    pub method_parameter: String,
+    pub shock_active_parameter: String,
+}
+
+impl Default for Feedback {
+    fn default() -> Self {
+        Feedback {
+            enabled: true,
+            interval_ms: 250,
+            connected_parameter: "/avatar/parameters/ShockRS_Connected".to_string(),
+            armed_parameter: "/avatar/parameters/ShockRS_Armed".to_string(),
+            intensity_parameter: "/avatar/parameters/ShockRS_Intensity".to_string(),
+            method_parameter: "/avatar/parameters/ShockRS_Method".to_string(),
+            shock_active_parameter: "/avatar/parameters/ShockRS_Shocking".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct OscQuery {
+    pub enabled: bool,
+    // Service name other OSCQuery apps see
+    pub name: String,
+    // Send to wherever VRChat's OSCQuery service says instead of ip_address:send_port
+    pub discover_vrchat: bool,
+}
+
+impl Default for OscQuery {
+    fn default() -> Self {
+        OscQuery {
+            enabled: false,
+            name: "ShockRS".to_string(),
+            discover_vrchat: true,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Relay {
+    // ip:port of every OSC app to forward to, empty turns the relay off
+    pub targets: Vec<String>,
+    // OSC address patterns to forward, empty forwards everything
+    pub addresses: Vec<String>,
+    // Packets held per target before it starts dropping them
+    pub queue_size: usize,
+}
+
+impl Default for Relay {
+    fn default() -> Self {
+        Relay {
+            targets: Vec::new(),
+            addresses: Vec::new(),
+            queue_size: 64,
+        }
+    }
+}
+
+// PiShock uses firmware.api_authtoken as the API key
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct PiShock {
+    pub username: String,
+    // Shown in the PiShock logs as the sender
+    pub name: String,
+    pub api_url: String,
+    // Touchpoint ID -> PiShock share code
+    pub share_codes: HashMap<String, String>,
+}
+
+impl Default for PiShock {
+    fn default() -> Self {
+        PiShock {
+            username: String::new(),
+            name: "ShockRS".to_string(),
+            api_url: "https://do.pishock.com/api".to_string(),
+            share_codes: HashMap::new(),
+        }
+    }
+}
+
 // Make CONFIG a public static so it's accessible from other modules
 pub static CONFIG: Lazy<Config> = Lazy::new(|| {
     let config_path = "config.toml";
@@ -77,10 +269,39 @@ fn create_config() -> io::Result<()> {
     # This is the IP Address of the computer with the OSC Client on it (EX: VRChat) 
     # Default: 127.0.0.1 (LocalHost)
     ip_address = "127.0.0.1"
+
+    # This is the Prefix expected from OSC for Shocker touchpoints
+    # Touchpoint addresses starting with / are used as is
+    # Default: /avatar/parameters/
+    touchpointPrefix = "/avatar/parameters/"
+
+    # The interface RustyShock listens on, separate from ip_address so VRChat on another PC can be reached without listening everywhere
+    # EX: 0.0.0.0 (every interface)
+    # Default: "" (Same as ip_address)
+    bind_address = ""
+
+    # Only accept OSC from these IPs or CIDR ranges, anything else is dropped and counted
+    # EX: ["127.0.0.1", "::1", "192.168.1.0/24"]
+    # Default: [] (Accept from anyone who can reach listen_port)
+    allowed_sources = []
+
+    # Senders that are not on this computer have to put this in front of every address (EX: /hunter2/avatar/parameters/...)
+    # It is stripped before touchpoints see the address, loopback senders like a local VRChat don't need it
+    # Default: "" (Off)
+    secret_prefix = ""
+
+    # Also listen for OSC over TCP on this port, for controllers and bridges that send long sequences and need them in order
+    # Uses bind_address, allowed_sources and secret_prefix the same as UDP
+    # Default: 0 (Off)
+    tcp_port = 0
+    # How TCP packets are framed, slip (OSC 1.1) or length (OSC 1.0, int32 size before each packet)
+    # Default: slip
+    tcp_framing = "slip"
     
     [firmware]
     # The firmware your controller device is using
-    # Options: legacy, openshock, pishock
+    # Options: legacy, openshock, openshock_live (OpenShock over a live SignalR connection), pishock, mock (logs commands without sending them)
+    # Several can run side by side, separated by commas (EX: "legacy, openshock")
     # Default: legacy (When OpenShock 1.0 is released, this will be changed)
     firmware = "legacy"
     # This is the endpoint used for your specific firmware
@@ -103,6 +324,124 @@ fn create_config() -> io::Result<()> {
     # options: touchpoint_router,api_router,osc_router,world_command_router
     # default: ["",""]
     disabled_features = ["",""]
+
+    [pishock]
+    # Only used when firmware includes pishock, the API key is firmware.api_authtoken
+    # Your PiShock username
+    # Default: ""
+    username = ""
+    # Name shown in the PiShock logs
+    # Default: ShockRS
+    name = "ShockRS"
+    # Default: https://do.pishock.com/api
+    api_url = "https://do.pishock.com/api"
+    # Maps the ids used in touchpoints.toml to PiShock share codes
+    # EX: share_codes = { collar = "17519CD8GAP" } and ids = ["collar"] in touchpoints.toml
+    # PiShock only takes whole seconds (1 - 15), shorter touches are rounded up to 1 s and [safety] charges the full second
+    # PiShock has no stop either, a stop sends the weakest vibrate it accepts (intensity 1 for 1 s) to replace what is running
+    # Default: {}
+    share_codes = {}
+
+    [world_command]
+    # Folder VRChat writes its output_log_*.txt files to
+    # Leave blank to detect it: %APPDATA%\..\LocalLow\VRChat\VRChat on Windows, the Steam Proton prefix on Linux
+    # Default: ""
+    log_directory = ""
+
+    [emergency_stop]
+    # OSC address that engages the emergency stop (EX: an avatar menu button)
+    # Every shocker is stopped and nothing is sent until it is re-armed
+    # Default: /avatar/parameters/ShockRS_EStop
+    osc_address = "/avatar/parameters/ShockRS_EStop"
+    # OSC address that re-arms after an emergency stop (EX: /avatar/parameters/ShockRS_Arm)
+    # Off by default, anyone who can send us OSC could re-arm with it. Blank only allows "rusty-shock arm"
+    # Default: ""
+    rearm_osc_address = ""
+    # Local control port (127.0.0.1 only) used by "rusty-shock estop", "rusty-shock arm" and "rusty-shock status"
+    # Requests from a browser (with an Origin header) or without the X-ShockRS-Token header are refused
+    # 0 disables it
+    # Default: 9150
+    control_port = 9150
+    # Shared secret for the control port, the CLI sends it in X-ShockRS-Token. Blank only requires the header
+    # Default: ""
+    control_token = ""
+
+    [safety]
+    # Applied to everything right before it is sent, no touchpoint or World Command can go past these
+    # Highest intensity a shock can be sent at, 0.0 - 1.0
+    # Default: 1.0
+    max_shock_intensity = 1.0
+    # Highest intensity a vibration can be sent at, 0.0 - 1.0
+    # Default: 1.0
+    max_vibrate_intensity = 1.0
+    # Total seconds of shock allowed in any 60 second window, across all shockers. 0 disables
+    # Charged with what the shocker really runs, EX: a 50 ms touch costs 1 s on PiShock and 300 ms on OpenShock
+    # Shockers firing at the same time split what is left, a shock that can't fit is dropped
+    # Default: 30
+    shock_seconds_per_minute = 30.0
+    # Total seconds of shock allowed until Rusty Shock is restarted, across all shockers. 0 disables
+    # Default: 0
+    shock_seconds_per_session = 0.0
+    # Minimum milliseconds between the end of one shock and the start of the next on the same shocker
+    # Default: 0
+    min_shock_gap_ms = 0
+    # A held contact keeps firing on its own (VRChat only sends a value when it changes), these stop one that is stuck
+    # Milliseconds a contact can be held before it is let go, it has to be released before it can fire again. 0 disables
+    # Default: 30000
+    max_hold_ms = 30000
+    # Milliseconds without a new value before a held contact is let go. 0 disables
+    # A perfectly still contact (EX: a bool contact) sends nothing, so it also ends after this long
+    # Default: 10000
+    stale_hold_ms = 10000
+
+    [feedback]
+    # Avatar parameters Rusty Shock sends back to the OSC client (ip_address:send_port) so avatars can show status
+    # Default: true
+    enabled = true
+    # How often the parameters are checked, only changed values are sent (everything is resent every few seconds)
+    # Default: 250
+    interval_ms = 250
+    # Bool, every firmware backend is connected. Leave any address blank to not send it
+    # Default: /avatar/parameters/ShockRS_Connected
+    connected_parameter = "/avatar/parameters/ShockRS_Connected"
+    # Bool, false while the emergency stop is engaged
+    # Default: /avatar/parameters/ShockRS_Armed
+    armed_parameter = "/avatar/parameters/ShockRS_Armed"
+    # Float, intensity of the last command sent (0.0 - 1.0)
+    # Default: /avatar/parameters/ShockRS_Intensity
+    intensity_parameter = "/avatar/parameters/ShockRS_Intensity"
+    # Int, method of the last command sent (1 shock, 2 vibrate, 3 sound)
+    # Default: /avatar/parameters/ShockRS_Method
+    method_parameter = "/avatar/parameters/ShockRS_Method"
+    # Bool, a shock is being sent right now
+    # Default: /avatar/parameters/ShockRS_Shocking
+    shock_active_parameter = "/avatar/parameters/ShockRS_Shocking"
+
+    [oscquery]
+    # Advertise Rusty Shock over OSCQuery (mDNS + HTTP) so VRChat finds it and sends to it on its own
+    # Set osc.listen_port = 0 alongside this to let the OS pick a free port, no more port clashes with other OSC apps
+    # Default: false
+    enabled = false
+    # Name shown to other OSCQuery apps
+    # Default: ShockRS
+    name = "ShockRS"
+    # Look for VRChat's OSCQuery service and send feedback to it instead of ip_address:send_port
+    # Also reads the avatar VRChat has on when found, so per avatar touchpoints are right without changing avatars first
+    # Default: true
+    discover_vrchat = true
+
+    [relay]
+    # Forward the OSC we receive to other apps, VRChat only sends to one port (EX: ["127.0.0.1:9002", "127.0.0.1:9003"])
+    # Default: [] (Relay off)
+    # Don't point two relays at each other (EX: another OSC router relaying back to us), packets would loop forever
+    targets = []
+    # Only forward these addresses, same wildcards as touchpoints and without a leading / they are relative to touchpointPrefix
+    # EX: ["/avatar/change", "FT/*"]
+    # Default: [] (Everything)
+    addresses = []
+    # Packets waiting per target, a target that falls further behind loses packets instead of slowing down shocks
+    # Default: 64
+    queue_size = 64
     "#;
 
     //for some odd reason if I dont do the conversion to bytes it wont write to the file even with as_bytes in write_all
@@ -1,18 +1,17 @@
-use osc::touchpoints::CommandState;
 use serde::de;
 use swing::Logger;
 use tokio::sync::mpsc;
 use std::sync::Arc;
-use rosc::OscMessage;
-use std::collections::HashMap;
-use tokio::sync::Mutex;
 
 // MODULES BABBBBBYYYYYY
+mod backend;
 mod config;
 mod osc;
 mod openshock_legacy;
 mod openshock;
 mod pishock;
+mod safety;
+mod shutdown;
 mod world_command;
 
 // New type for WorldCommand that defines the data we want to extract from the log file
@@ -30,32 +29,59 @@ async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
     let logging_config = config::get_logging_config();
     Logger::with_config(logging_config).init().unwrap();
 
+    // rusty-shock estop | arm | status talk to the running instance and exit
+    if let Some(command) = std::env::args().nth(1) {
+        return safety::estop::run_cli(&command).await;
+    }
+
     // initialize the features config
     let features_config = config::get_features_config();
 
-    // Tasks Vector for Tokio tasks
-    let mut tasks = Vec::new();
+    // Ctrl+C / SIGTERM stop the tasks stage by stage, then every shocker gets a final stop
+    let shutdown = shutdown::Shutdown::new();
+    tokio::spawn(shutdown::listen_for_signals(shutdown.clone()));
+    // Everything that feeds the command map or reads backend state: OSC servers, World Command, control endpoint, feedback
+    let mut inputs = shutdown::Stage::new("inputs");
+    // The touchpoint router, stopped once nothing new reaches it
+    let mut touchpoint_router = shutdown::Stage::new("touchpoint router");
+    // The backend router, stopped once the command map no longer changes
+    let mut backend_router = shutdown::Stage::new("backend router");
 
     // Initialize the command map and pass it to the OSC handler task
     let command_states = osc::touchpoints::initialize_commandmap().await;
     let command_states_clone = Arc::clone(&command_states);
+    let command_states_shutdown = Arc::clone(&command_states);
     log::info!("Rusty Shock has started");
     log::debug!("Disabled features: {:?}", features_config.disabled_features);
 
 
     // Check if osc_router is in the disabled features list
-    let (_osc_tx, osc_rx) = if features_config.disabled_features.contains(&"osc_router".to_string()) {
+    let osc_parameters = if features_config.disabled_features.contains(&"osc_router".to_string()) {
         log::warn!("OSC Router is disabled in the config.toml file. Please remove it from the disabled_features list to enable it.");
-        (None, None)
+        None
     } else {
         log::info!("OSC Router is enabled.");
-        let (tx, rx) = mpsc::channel::<OscMessage>(1);
-        let tx_clone = tx.clone(); // Clone the transmitter
-        // Spawn the OSC server task, passing it the sender part of the channel
-        let _server_handle = tasks.push(tokio::spawn(async move {
-            osc::osc::start_osc_server(tx_clone).await.expect("OSC server failed");
-        }));
-        (Some(tx), Some(rx))
+        // Latest value per OSC address, shared between the OSC server and the touchpoint router
+        let parameters = Arc::new(osc::parameters::ParameterStore::new());
+        let parameters_clone = Arc::clone(&parameters);
+        // One allowlist for UDP and TCP, so a source is only warned about once
+        let sources = Arc::new(std::sync::Mutex::new(osc::source::SourceFilter::new()?));
+        let udp_sources = Arc::clone(&sources);
+        // Spawn the OSC server task, it writes into the parameter store
+        inputs.spawn(async move {
+            osc::osc::start_osc_server(parameters_clone, udp_sources).await.expect("OSC server failed");
+        });
+        // OSC over TCP feeds the same parameter store
+        if config::get_config().osc.tcp_port != 0 {
+            let tcp_parameters = Arc::clone(&parameters);
+            let tcp_shutdown = inputs.shutdown();
+            inputs.spawn(async move {
+                if let Err(e) = osc::tcp::start_tcp_server(tcp_parameters, sources, tcp_shutdown).await {
+                    log::error!("OSC TCP server failed: {}", e);
+                }
+            });
+        }
+        Some(parameters)
     };
 
     // Check if world_command is in the disabled features list
@@ -67,13 +93,25 @@ async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
         
         // Create a channel for WorldCommand messages
         let (tx, rx) = mpsc::channel::<WorldCommandEvent>(1);
-        let _tx_clone = tx.clone(); // Clone the transmitter
-        let _world_command_handle = tasks.push(tokio::spawn(async move {
-            //world_command::handler::start_world_command_server(tx_clone).await.unwrap();
-        }));
+        let tx_clone = tx.clone(); // Clone the transmitter
+        inputs.spawn(async move {
+            if let Err(e) = world_command::handler::start_world_command_server(tx_clone).await {
+                log::error!("World Command failed: {}", e);
+            }
+        });
         (Some(tx), Some(rx))
     };
 
+    // Local emergency stop endpoint, also used by the estop/arm/status CLI commands
+    let control_port = config::get_config().emergency_stop.control_port;
+    if control_port != 0 {
+        inputs.spawn(async move {
+            if let Err(e) = safety::estop::start_control_server(control_port).await {
+                log::error!("Emergency stop control endpoint failed: {}", e);
+            }
+        });
+    }
+
     // Check if touchpoints is in the disabled features list
     log::debug!("Disabled features before touchpoints check: {:?}", features_config.disabled_features);
     if features_config.disabled_features.contains(&"touchpoint_router".to_string()) {
@@ -87,56 +125,52 @@ async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
         // Pass the desired delay in milliseconds here
         let delay_ms = 50; // for a 100 ms delay
         // Spawn the API handler task
-        let _touchpoints_handle = tasks.push(tokio::spawn(async move {
-            // Directly pass osc_rx and world_command_rx as they are already Option types
-            if let Err(e) = osc::touchpoints::display_and_handle_touchpoints(osc_rx, world_command_rx, command_states, delay_ms).await {
+        touchpoint_router.spawn(async move {
+            // Directly pass osc_parameters and world_command_rx as they are already Option types
+            if let Err(e) = osc::touchpoints::display_and_handle_touchpoints(osc_parameters, world_command_rx, command_states, delay_ms).await {
                 log::error!("Error in display_and_handle_touchpoints: {:?}", e);
             }
-        }));
+        });
         
     // Check if firmware is in the disabled features list
-    if features_config.disabled_features.contains(&"api_router".to_string()) {
+    let backends = if features_config.disabled_features.contains(&"api_router".to_string()) {
+        // Keep running so the touchpoint router still fills the command map
         log::warn!("Firmware is disabled in the config.toml file. Please remove it from the disabled_features list to enable it.");
-        return Ok(())
+        Vec::new()
     } else {
         log::info!("Firmware is enabled.");
-        let _firmware_handle = tasks.push(tokio::spawn(async {
-            identify_firmware_start_server(command_states_clone).await;
-        }));
+        // Backends come from firmware.firmware in config.toml, see backend::build_backends
+        let backends = backend::build_backends().await;
+        let backends_clone = backends.clone();
+        let router_shutdown = backend_router.shutdown();
+        // Not cancelled, the router finishes its current batch and returns by itself
+        backend_router.spawn_graceful(async move {
+            backend::router::run(backends_clone, command_states_clone, router_shutdown).await;
+        });
+        backends
+    };
+
+    // Status parameters back to VRChat
+    if config::get_config().feedback.enabled {
+        let feedback_backends = backends.clone();
+        inputs.spawn(async move {
+            if let Err(e) = osc::feedback::start_feedback(feedback_backends).await {
+                log::error!("OSC feedback failed: {}", e);
+            }
+        });
     }
 
+    let reason = shutdown.wait_shutdown_triggered().await;
+    log::info!("Shutdown ({}): stopping tasks", reason);
 
-    // Wait for all the spawned tasks to complete
-    for task in tasks {
-        let _ = task.await?;
-    }
+    // Inputs first so nothing new reaches the command map, then the routers, then stop and close the backends
+    inputs.stop(reason).await;
+    touchpoint_router.stop(reason).await;
+    backend_router.stop(reason).await;
+    shutdown::stop_all_shockers(&command_states_shutdown, &backends).await;
+    log::info!("Rusty Shock has stopped");
 
     Ok(())
 }
 }
 
-
-async fn identify_firmware_start_server(commandmap: Arc<Mutex<HashMap<String, CommandState>>>){
-    log::debug!("Firmware Router: Starting Web Server");
-
-    let config = config::get_config();
-
-    match config.firmware.firmware.to_ascii_lowercase().as_str(){
-        "legacy" => {
-            log::debug!("Legacy Touchpoint");
-            let commandmap_clone = Arc::clone(&commandmap);
-            openshock_legacy::handler::handler(config.firmware.api_endpoint.clone(),commandmap_clone).await;
-        },
-        "openshock" => {
-            log::debug!("OpenShock Touchpoint");
-            let commandmap_clone = Arc::clone(&commandmap);
-            openshock::handler::handler(config.firmware.api_endpoint.clone(),commandmap_clone).await;
-        },
-        "pishock" => {
-            log::debug!("PiShock Touchpoint");
-            log::warn!("PiShock is not yet implemented");
-            //pishock::websocket::start_websocket_server().await;
-        }
-        _ => log::error!("Unknown touchpoint firmware: {}", config.firmware.firmware),
-    }
-}
\ No newline at end of file
@@ -0,0 +1,154 @@
+// Thin client over the OpenShock HTTP API, see "APIDocs for OpenShock" in the repo root
+use crate::backend::BackendError;
+use reqwest::{Client, StatusCode, header::HeaderMap};
+use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
+
+// OpenShock ControlType
+pub const CONTROL_STOP: u8 = 0;
+pub const CONTROL_SHOCK: u8 = 1;
+pub const CONTROL_VIBRATE: u8 = 2;
+pub const CONTROL_SOUND: u8 = 3;
+
+// Shows up in the OpenShock logs as the sender of the command
+pub const CUSTOM_NAME: &str = "ShockRS";
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Control {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub control_type: u8,
+    pub intensity: u8,   // 0 - 100
+    pub duration: u32,   // 300 - 30000 ms
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ControlRequest<'a> {
+    shocks: &'a [Control],
+    custom_name: &'a str,
+}
+
+#[derive(Deserialize)]
+struct ApiResponse<T> {
+    data: T,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Device {
+    pub id: String,
+    pub name: String,
+    pub shockers: Vec<Shocker>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Shocker {
+    pub id: String,
+    pub name: String,
+    pub is_paused: bool,
+}
+
+pub struct OpenShockApi {
+    client: Client,
+    base_url: String,
+}
+
+impl OpenShockApi {
+    pub fn new(api_endpoint: &str, auth_token: &str) -> Result<OpenShockApi, BackendError> {
+        let mut headers = HeaderMap::new();
+        let token = auth_token.parse()
+            .map_err(|_| BackendError::Unauthorized("Auth token contains characters that can't be sent in a header".to_string()))?;
+        headers.insert("OpenShockToken", token);
+
+        let client = Client::builder()
+            .default_headers(headers)
+            .user_agent(concat!("ShockRS/", env!("CARGO_PKG_VERSION")))
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| BackendError::Connection(e.to_string()))?;
+
+        Ok(OpenShockApi {
+            client,
+            base_url: base_url(api_endpoint),
+        })
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    // POST /2/shockers/control
+    pub async fn control(&self, shocks: &[Control]) -> Result<(), BackendError> {
+        let body = ControlRequest {
+            shocks,
+            custom_name: CUSTOM_NAME,
+        };
+        let response = self.client.post(format!("{}/2/shockers/control", self.base_url))
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| BackendError::Connection(e.to_string()))?;
+        check_status(response).await?;
+        Ok(())
+    }
+
+    // GET /1/shockers/own, every hub with its shockers
+    pub async fn own_shockers(&self) -> Result<Vec<Device>, BackendError> {
+        let response = self.client.get(format!("{}/1/shockers/own", self.base_url))
+            .send()
+            .await
+            .map_err(|e| BackendError::Connection(e.to_string()))?;
+        let response = check_status(response).await?;
+        let devices: ApiResponse<Vec<Device>> = response.json().await
+            .map_err(|e| BackendError::Rejected(format!("Unexpected shocker list: {}", e)))?;
+        Ok(devices.data)
+    }
+
+    // GET /1/users/self, cheapest call that proves the endpoint is up and the token is valid
+    pub async fn check_auth(&self) -> Result<(), BackendError> {
+        let response = self.client.get(format!("{}/1/users/self", self.base_url))
+            .send()
+            .await
+            .map_err(|e| BackendError::Connection(e.to_string()))?;
+        check_status(response).await?;
+        Ok(())
+    }
+}
+
+// api_endpoint may be a bare host (api.shocklink.net) or a full URL (http://127.0.0.1:8080 for a local server)
+fn base_url(api_endpoint: &str) -> String {
+    let api_endpoint = api_endpoint.trim().trim_end_matches('/');
+    if api_endpoint.starts_with("http://") || api_endpoint.starts_with("https://") {
+        api_endpoint.to_string()
+    } else {
+        format!("https://{}", api_endpoint)
+    }
+}
+
+// Turn the HTTP status into the matching BackendError
+async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, BackendError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            Err(BackendError::Unauthorized(format!("OpenShock returned {}, check api_authtoken in config.toml", status)))
+        },
+        StatusCode::TOO_MANY_REQUESTS => {
+            // Retry-After is in seconds
+            let retry_after = response.headers().get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .map(Duration::from_secs);
+            Err(BackendError::RateLimited(retry_after))
+        },
+        _ => {
+            let body = response.text().await.unwrap_or_default();
+            Err(BackendError::Rejected(format!("OpenShock returned {}: {}", status, body)))
+        },
+    }
+}
@@ -1,24 +1,472 @@
 // https://api.shocklink.net/swagger/index.html
 // https://github.com/OpenShock
 // API Test Auth token ziaMvnhog1l3v9W2pnLqjo7XthKwJK7dV4HVh73NJWieMfsidAKFlwkrm3GrO8y6
-use crate::{config,osc::touchpoints::CommandState};
-use tokio::sync::Mutex;
-use std::sync::Arc;
+use crate::backend::{BackendError, BackendHealth, Sent, ShockerBackend, ShockerCommand, ShockerInfo, METHOD_SHOCK, METHOD_VIBRATE, METHOD_SOUND};
+use crate::openshock::api::{self, Control, OpenShockApi};
+use crate::openshock::signalr::{self, HubClient};
+use crate::osc::touchpoints;
+use async_trait::async_trait;
+use serde_json::json;
 use std::collections::HashMap;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+// OpenShock rejects anything outside these
+const MIN_DURATION_MS: u64 = 300;
+const MAX_DURATION_MS: u64 = 30000;
+
+// Used when the API rate limits us without a Retry-After header
+const DEFAULT_RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(5);
+
+// How long a stop over the hub may take before falling back to the HTTP API, well inside the shutdown timeout
+const HUB_STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct OpenShockBackend {
+    api: OpenShockApi,
+    // (shocker ID, control type) -> intensity and until when, so a held contact isn't resent every router pass
+    // A touchpoint with several methods sends one control per type to the same shocker, each is tracked on its own
+    running: Mutex<HashMap<(String, u8), (u8, Instant)>>,
+    rate_limited_until: Mutex<Option<Instant>>,
+}
+
+impl OpenShockBackend {
+    pub fn new(api_endpoint: &str, auth_token: &str) -> Result<OpenShockBackend, BackendError> {
+        log::debug!("Openshock Touchpoint Handler");
+        log::debug!("API Endpoint: {}",api_endpoint);
+
+        // verify the auth token is not blank or other error before dispatching to endpoints/auth.rs
+        if auth_token.is_empty() {
+            return Err(BackendError::Unauthorized("Auth token is blank. Please check your config.toml file.".to_string()));
+        } else {
+            log::debug!("Auth token is not blank. Continuing.");
+        }
+
+        Ok(OpenShockBackend {
+            api: open_api(api_endpoint, auth_token)?,
+            running: Mutex::new(HashMap::new()),
+            rate_limited_until: Mutex::new(None),
+        })
+    }
+
+    // Send through the API, remembering a 429 so we back off instead of hammering it
+    // Ok(false) when skipped because of an earlier 429
+    async fn send(&self, shocks: &[Control]) -> Result<bool, BackendError> {
+        {
+            let mut rate_limited_until = self.rate_limited_until.lock().await;
+            match *rate_limited_until {
+                Some(until) if until > Instant::now() => {
+                    log::debug!("OpenShock rate limited for another {:?}, skipping {} control(s)", until - Instant::now(), shocks.len());
+                    return Ok(false);
+                },
+                Some(_) => *rate_limited_until = None,
+                None => {},
+            }
+        }
+
+        log::debug!("OpenShock control: {:?}", shocks);
+        let result = self.api.control(shocks).await;
+        if let Err(BackendError::RateLimited(retry_after)) = &result {
+            let backoff = retry_after.unwrap_or(DEFAULT_RATE_LIMIT_BACKOFF);
+            log::warn!("OpenShock rate limited, backing off for {:?}", backoff);
+            *self.rate_limited_until.lock().await = Some(Instant::now() + backoff);
+        }
+        result.map(|_| true)
+    }
+}
+
+#[async_trait]
+impl ShockerBackend for OpenShockBackend {
+    fn name(&self) -> &'static str {
+        "OpenShock"
+    }
+
+    async fn send_control(&self, commands: &[ShockerCommand]) -> Result<Vec<Sent>, BackendError> {
+        let now = Instant::now();
+        let mut running = self.running.lock().await;
+        let mut shocks = Vec::new();
+        let controls = to_controls(commands);
+
+        // Released since the last pass but OpenShock still has time left on it, it only stops when told to
+        running.retain(|_, &mut (_, until)| until > now);
+        let mut released: Vec<&String> = running.keys()
+            .map(|(id, _)| id)
+            .filter(|id| !controls.iter().any(|control| &control.id == *id))
+            .collect();
+        released.sort();
+        released.dedup();
+        let stops: Vec<Control> = released.into_iter().map(|id| stop_control(id.clone())).collect();
+        if !stops.is_empty() {
+            // A stop must go out even while rate limited
+            log::debug!("OpenShock stop: {:?}", stops);
+            self.api.control(&stops).await?;
+            running.retain(|(id, _), _| !stops.iter().any(|stop| &stop.id == id));
+        }
+        // A type the shocker no longer gets is replaced by what is sent now, so it isn't running anymore either
+        running.retain(|(id, control_type), _| {
+            !controls.iter().any(|control| &control.id == id)
+                || controls.iter().any(|control| &control.id == id && control.control_type == *control_type)
+        });
+
+        for control in controls {
+            // Still running with the same intensity, let it finish instead of resending
+            let key = (control.id.clone(), control.control_type);
+            if let Some(&(running_intensity, until)) = running.get(&key) {
+                if running_intensity == control.intensity && until > now {
+                    continue;
+                }
+            }
+
+            running.insert(key, (control.intensity, now + Duration::from_millis(control.duration as u64)));
+            shocks.push(control);
+        }
+        drop(running);
+
+        if shocks.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let result = self.send(&shocks).await;
+        if !matches!(result, Ok(true)) {
+            // Nothing is running if the request failed or was skipped, so the next pass tries again
+            let mut running = self.running.lock().await;
+            for shock in &shocks {
+                running.remove(&(shock.id.clone(), shock.control_type));
+            }
+        }
+        match result? {
+            true => Ok(shocks.iter().map(sent).collect()),
+            false => Ok(Vec::new()),
+        }
+    }
+
+    fn hardware_duration(&self, command: &ShockerCommand) -> Option<Duration> {
+        hardware_duration(command)
+    }
+
+    async fn stop_all(&self) -> Result<(), BackendError> {
+        self.running.lock().await.clear();
+
+        let stops = stop_controls();
+        if stops.is_empty() {
+            return Ok(());
+        }
+        // A stop must go out even while rate limited
+        self.api.control(&stops).await
+    }
+
+    async fn stop(&self, ids: &[String]) -> Result<(), BackendError> {
+        self.running.lock().await.retain(|(id, _), _| !ids.contains(id));
+
+        let stops = own_stops(ids);
+        if stops.is_empty() {
+            return Ok(());
+        }
+        self.api.control(&stops).await
+    }
+
+    async fn list_shockers(&self) -> Result<Vec<ShockerInfo>, BackendError> {
+        list_shockers(&self.api).await
+    }
+
+    async fn health(&self) -> BackendHealth {
+        match self.api.check_auth().await {
+            Ok(()) => BackendHealth::Connected,
+            Err(e) => BackendHealth::Disconnected(e.to_string()),
+        }
+    }
+
+    // Every request is awaited in send_control, so nothing is left in flight
+    async fn close(&self) -> Result<(), BackendError> {
+        Ok(())
+    }
+}
+
+// Low latency mode, control frames are streamed over the SignalR user hub every router pass
+// The HTTP API is still used for listing shockers and as a fallback for stop
+pub struct OpenShockLiveBackend {
+    api: OpenShockApi,
+    hub: HubClient,
+}
+
+impl OpenShockLiveBackend {
+    pub fn new(api_endpoint: &str, auth_token: &str) -> Result<OpenShockLiveBackend, BackendError> {
+        log::debug!("Openshock Live Touchpoint Handler");
+
+        if auth_token.is_empty() {
+            return Err(BackendError::Unauthorized("Auth token is blank. Please check your config.toml file.".to_string()));
+        }
+
+        let api = open_api(api_endpoint, auth_token)?;
+        let hub_url = signalr::hub_url(api.base_url());
+        log::debug!("OpenShock hub URL: {}", hub_url);
+
+        Ok(OpenShockLiveBackend {
+            hub: HubClient::start(hub_url, auth_token.to_string()),
+            api,
+        })
+    }
+}
+
+impl OpenShockLiveBackend {
+    // Only trust the hub once it confirms the stop, otherwise the HTTP API gets it too
+    async fn send_stops(&self, stops: Vec<Control>) -> Result<(), BackendError> {
+        if stops.is_empty() {
+            return Ok(());
+        }
+        match self.hub.invoke_confirmed("ControlV2", json!([stops, api::CUSTOM_NAME]), HUB_STOP_TIMEOUT).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                log::warn!("OpenShock hub stop failed ({}), falling back to the HTTP API", e);
+                self.api.control(&stops).await
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ShockerBackend for OpenShockLiveBackend {
+    fn name(&self) -> &'static str {
+        "OpenShock Live"
+    }
+
+    async fn send_control(&self, commands: &[ShockerCommand]) -> Result<Vec<Sent>, BackendError> {
+        let controls = to_controls(commands);
+        if controls.is_empty() {
+            return Ok(Vec::new());
+        }
+        log::debug!("OpenShock hub control: {:?}", controls);
+        self.hub.invoke("ControlV2", json!([controls, api::CUSTOM_NAME]))?;
+        // Every frame replaces what is running, the limiter only charges what each one adds
+        Ok(controls.iter().map(sent).collect())
+    }
 
-pub async fn handler(websocket_url: String,commandmap: Arc<Mutex<HashMap<String, CommandState>>>) {
-    log::debug!("Openshock Touchpoint Handler");
-    log::debug!("WebSocket URL: {}",websocket_url);
-    let firmware_config = &config::get_config().firmware;
+    fn hardware_duration(&self, command: &ShockerCommand) -> Option<Duration> {
+        hardware_duration(command)
+    }
+
+    async fn stop_all(&self) -> Result<(), BackendError> {
+        self.send_stops(stop_controls()).await
+    }
+
+    async fn stop(&self, ids: &[String]) -> Result<(), BackendError> {
+        self.send_stops(own_stops(ids)).await
+    }
+
+    async fn list_shockers(&self) -> Result<Vec<ShockerInfo>, BackendError> {
+        list_shockers(&self.api).await
+    }
+
+    async fn health(&self) -> BackendHealth {
+        if self.hub.is_connected() {
+            BackendHealth::Connected
+        } else {
+            BackendHealth::Disconnected("OpenShock hub is reconnecting".to_string())
+        }
+    }
+
+    // Anything still queued for the hub goes out before the connection closes
+    async fn close(&self) -> Result<(), BackendError> {
+        self.hub.close().await
+    }
+}
+
+fn open_api(api_endpoint: &str, auth_token: &str) -> Result<OpenShockApi, BackendError> {
+    let api = OpenShockApi::new(api_endpoint, auth_token)?;
+    log::debug!("OpenShock API URL: {}", api.base_url());
+    Ok(api)
+}
+
+// OpenShock shocker IDs are UUIDs, anything else belongs to another backend
+fn is_openshock_id(id: &str) -> bool {
+    uuid::Uuid::parse_str(id).is_ok()
+}
+
+fn control_type(method: u8) -> Option<u8> {
+    match method {
+        METHOD_SHOCK => Some(api::CONTROL_SHOCK),
+        METHOD_VIBRATE => Some(api::CONTROL_VIBRATE),
+        METHOD_SOUND => Some(api::CONTROL_SOUND),
+        _ => None,
+    }
+}
+
+// OpenShock runs anything shorter than its minimum for the minimum
+fn hardware_duration(command: &ShockerCommand) -> Option<Duration> {
+    is_openshock_id(&command.id).then(|| Duration::from_millis(command.duration.clamp(MIN_DURATION_MS, MAX_DURATION_MS)))
+}
+
+fn sent(control: &Control) -> Sent {
+    let method = match control.control_type {
+        api::CONTROL_SHOCK => METHOD_SHOCK,
+        api::CONTROL_VIBRATE => METHOD_VIBRATE,
+        _ => METHOD_SOUND,
+    };
+    Sent::new(&control.id, method, Duration::from_millis(control.duration as u64))
+}
+
+// Scale our commands to the ranges OpenShock accepts
+fn to_controls(commands: &[ShockerCommand]) -> Vec<Control> {
+    commands.iter()
+        .filter(|command| is_openshock_id(&command.id))
+        .filter_map(|command| {
+            let control_type = match control_type(command.method) {
+                Some(control_type) => control_type,
+                None => {
+                    log::error!("OpenShock has no control type for method {}", command.method);
+                    return None;
+                }
+            };
+            Some(Control {
+                id: command.id.clone(),
+                control_type,
+                intensity: (command.intensity.clamp(0.0, 1.0) * 100.0).round() as u8,
+                duration: command.duration.clamp(MIN_DURATION_MS, MAX_DURATION_MS) as u32,
+            })
+        })
+        .collect()
+}
+
+// A stop for every OpenShock shocker in touchpoints.toml
+fn stop_controls() -> Vec<Control> {
+    let mut ids: Vec<String> = touchpoints::get_config().all_devices()
+        .flat_map(|device| device.ids.iter())
+        .filter(|id| is_openshock_id(id))
+        .cloned()
+        .collect();
+    ids.sort();
+    ids.dedup();
+
+    ids.into_iter().map(stop_control).collect()
+}
+
+// Stops for the given IDs that are OpenShock shockers
+fn own_stops(ids: &[String]) -> Vec<Control> {
+    ids.iter().filter(|id| is_openshock_id(id)).cloned().map(stop_control).collect()
+}
+
+fn stop_control(id: String) -> Control {
+    Control {
+        id,
+        control_type: api::CONTROL_STOP,
+        intensity: 0,
+        duration: MIN_DURATION_MS as u32,
+    }
+}
+
+async fn list_shockers(api: &OpenShockApi) -> Result<Vec<ShockerInfo>, BackendError> {
+    let devices = api.own_shockers().await?;
+    Ok(devices.into_iter().flat_map(|device| {
+        let device_name = device.name;
+        device.shockers.into_iter().map(move |shocker| ShockerInfo {
+            id: shocker.id,
+            name: format!("{} / {}", device_name, shocker.name),
+            paused: shocker.is_paused,
+        })
+    }).collect())
+}
 
-    // get the auth token from the config
-    let auth_token = &firmware_config.api_authtoken;
-    // verify the auth token is not blank or other error before dispatching to endpoints/auth.rs
-    if auth_token.is_empty() {
-        log::error!("Auth token is blank. Please check your config.toml file.");
-        return;
-    } else {
-        log::debug!("Auth token is not blank. Continuing.");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::test_server::TestServer;
+    use serde_json::Value;
+
+    const SHOCKER: &str = "8d2a6f3e-5c1b-4e7a-9f0d-2b3c4d5e6f70";
+
+    fn shock(intensity: f32, duration: u64) -> ShockerCommand {
+        ShockerCommand { id: SHOCKER.to_string(), method: METHOD_SHOCK, intensity, duration }
+    }
+
+    // The control request carries the token header and OpenShock's ranges, a 50 ms touch goes out as OpenShock's 300 ms minimum
+    #[tokio::test]
+    async fn control_request_is_clamped_and_authorized() {
+        let mut server = TestServer::start(200, "").await;
+        let backend = OpenShockBackend::new(&server.url(), "test-token").unwrap();
+
+        // Charged as what OpenShock really runs
+        let sent = backend.send_control(&[shock(0.5, 50)]).await.unwrap();
+        assert_eq!(sent, vec![Sent::new(SHOCKER, METHOD_SHOCK, Duration::from_millis(300))]);
+
+        let request = server.request().await;
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/2/shockers/control");
+        assert_eq!(request.headers["openshocktoken"], "test-token");
+        assert!(request.headers["user-agent"].starts_with("ShockRS/"));
+        let body: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["customName"], "ShockRS");
+        assert_eq!(body["shocks"], serde_json::json!([{ "id": SHOCKER, "type": api::CONTROL_SHOCK, "intensity": 50, "duration": 300 }]));
+
+        // Held at the same intensity, the running shock is left alone and nothing more is charged
+        assert!(backend.send_control(&[shock(0.5, 50)]).await.unwrap().is_empty());
+        server.assert_idle();
+    }
+
+    // Letting go of a contact stops the shock instead of waiting for OpenShock's duration to run out
+    #[tokio::test]
+    async fn release_sends_a_stop() {
+        let mut server = TestServer::start(200, "").await;
+        let backend = OpenShockBackend::new(&server.url(), "test-token").unwrap();
+
+        backend.send_control(&[shock(1.0, 5000)]).await.unwrap();
+        server.request().await;
+
+        backend.send_control(&[]).await.unwrap();
+        let body: Value = serde_json::from_str(&server.request().await.body).unwrap();
+        assert_eq!(body["shocks"][0]["id"], SHOCKER);
+        assert_eq!(body["shocks"][0]["type"], api::CONTROL_STOP);
+
+        // Only once
+        backend.send_control(&[]).await.unwrap();
+        server.assert_idle();
     }
 
-}
\ No newline at end of file
+    // A touchpoint with methods [1, 2] shocks and vibrates the same shocker, holding it must not resend either
+    #[tokio::test]
+    async fn two_methods_on_one_shocker_are_deduplicated() {
+        let mut server = TestServer::start(200, "").await;
+        let backend = OpenShockBackend::new(&server.url(), "test-token").unwrap();
+        let vibrate = ShockerCommand { id: SHOCKER.to_string(), method: METHOD_VIBRATE, intensity: 0.5, duration: 5000 };
+
+        assert_eq!(backend.send_control(&[shock(0.5, 5000), vibrate.clone()]).await.unwrap().len(), 2);
+        let body: Value = serde_json::from_str(&server.request().await.body).unwrap();
+        assert_eq!(body["shocks"].as_array().unwrap().len(), 2);
+
+        assert!(backend.send_control(&[shock(0.5, 5000), vibrate.clone()]).await.unwrap().is_empty());
+        server.assert_idle();
+
+        // Letting go of both is a single stop for the shocker
+        backend.send_control(&[]).await.unwrap();
+        let body: Value = serde_json::from_str(&server.request().await.body).unwrap();
+        assert_eq!(body["shocks"], serde_json::json!([{ "id": SHOCKER, "type": api::CONTROL_STOP, "intensity": 0, "duration": 300 }]));
+    }
+
+    // Only our own shockers get a stop, and a stopped shock is sent again if it is still wanted
+    #[tokio::test]
+    async fn stop_only_sends_our_shockers() {
+        let mut server = TestServer::start(200, "").await;
+        let backend = OpenShockBackend::new(&server.url(), "test-token").unwrap();
+
+        backend.send_control(&[shock(1.0, 5000)]).await.unwrap();
+        server.request().await;
+
+        backend.stop(&[SHOCKER.to_string(), "3863".to_string()]).await.unwrap();
+        let body: Value = serde_json::from_str(&server.request().await.body).unwrap();
+        assert_eq!(body["shocks"], serde_json::json!([{ "id": SHOCKER, "type": api::CONTROL_STOP, "intensity": 0, "duration": 300 }]));
+
+        assert_eq!(backend.send_control(&[shock(1.0, 5000)]).await.unwrap().len(), 1);
+        server.request().await;
+        backend.stop(&["3863".to_string()]).await.unwrap();
+        server.assert_idle();
+    }
+
+    #[tokio::test]
+    async fn status_codes_map_to_backend_errors() {
+        let server = TestServer::start(401, "").await;
+        let backend = OpenShockBackend::new(&server.url(), "test-token").unwrap();
+        assert!(matches!(backend.send_control(&[shock(0.5, 1000)]).await, Err(BackendError::Unauthorized(_))));
+
+        let server = TestServer::start(500, "broken").await;
+        let backend = OpenShockBackend::new(&server.url(), "test-token").unwrap();
+        assert!(matches!(backend.send_control(&[shock(0.5, 1000)]).await, Err(BackendError::Rejected(_))));
+    }
+}
@@ -1,2 +1,4 @@
+pub mod api;
 pub mod handler;
+pub mod signalr;
 // Declare each submodule in the endpoints directory
@@ -0,0 +1,552 @@
+// Minimal SignalR client for the OpenShock user hub (/1/hubs/user)
+// Only the JSON hub protocol over WebSockets, which is all OpenShock needs:
+// https://github.com/dotnet/aspnetcore/blob/main/src/SignalR/docs/specs/HubProtocol.md
+use crate::backend::BackendError;
+use async_tungstenite::tokio::connect_async;
+use async_tungstenite::tungstenite::{Message, client::IntoClientRequest};
+use futures_util::{Sink, SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Duration, Instant};
+
+// Every SignalR JSON message ends with this
+const RECORD_SEPARATOR: char = '\u{1e}';
+
+// Hub message types
+const MESSAGE_INVOCATION: u64 = 1;
+const MESSAGE_COMPLETION: u64 = 3;
+const MESSAGE_PING: u64 = 6;
+const MESSAGE_CLOSE: u64 = 7;
+
+// SignalR defaults, the server drops us after 30 s of silence
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+const SERVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+
+// Frames waiting to go out, control frames are replaced every router pass so this stays small
+const OUTGOING_QUEUE: usize = 16;
+
+// What the hub answered to a confirmed invocation, Err holds the hub's error message
+type Confirmation = oneshot::Sender<Result<(), String>>;
+
+enum Outgoing {
+    // confirm is None for control frames, those are stale once the connection they were meant for is gone
+    // Confirmed invocations (stops) are kept across a reconnect and answered when the hub completes them
+    Invoke { target: String, arguments: Value, confirm: Option<Confirmation> },
+    // Everything queued before this has been written, close the socket and end the task
+    Close(oneshot::Sender<()>),
+}
+
+// How a connection ended without an error
+enum Ended {
+    ByServer,
+    Closed,
+}
+
+pub struct HubClient {
+    outgoing: mpsc::Sender<Outgoing>,
+    connected: Arc<AtomicBool>,
+}
+
+impl HubClient {
+    // Spawn the connection task, it keeps reconnecting until close is called
+    pub fn start(hub_url: String, auth_token: String) -> HubClient {
+        let (outgoing_tx, outgoing_rx) = mpsc::channel::<Outgoing>(OUTGOING_QUEUE);
+        let connected = Arc::new(AtomicBool::new(false));
+
+        let connected_clone = Arc::clone(&connected);
+        tokio::spawn(async move {
+            run_hub(hub_url, auth_token, outgoing_rx, connected_clone).await;
+        });
+
+        HubClient {
+            outgoing: outgoing_tx,
+            connected,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    // Queue an invocation without waiting for it, an Err means the frame was not queued
+    pub fn invoke(&self, target: &str, arguments: Value) -> Result<(), BackendError> {
+        if !self.is_connected() {
+            return Err(BackendError::Connection("OpenShock hub is not connected".to_string()));
+        }
+
+        let frame = Outgoing::Invoke { target: target.to_string(), arguments, confirm: None };
+        match self.outgoing.try_send(frame) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => Err(BackendError::Connection(format!("OpenShock hub queue is full, {} frame dropped", target))),
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(BackendError::Connection("OpenShock hub task has stopped".to_string())),
+        }
+    }
+
+    // Invoke and wait until the hub reports it done, for frames that must not get lost (EX: stops)
+    pub async fn invoke_confirmed(&self, target: &str, arguments: Value, timeout: Duration) -> Result<(), BackendError> {
+        if !self.is_connected() {
+            return Err(BackendError::Connection("OpenShock hub is not connected".to_string()));
+        }
+
+        let (confirm, confirmed) = oneshot::channel();
+        let frame = Outgoing::Invoke { target: target.to_string(), arguments, confirm: Some(confirm) };
+        let result = time::timeout(timeout, async {
+            self.outgoing.send(frame).await
+                .map_err(|_| BackendError::Connection("OpenShock hub task has stopped".to_string()))?;
+            // Dropped unanswered when the connection goes away
+            confirmed.await
+                .map_err(|_| BackendError::Connection(format!("OpenShock hub connection lost before {} was confirmed", target)))?
+                .map_err(|e| BackendError::Rejected(format!("OpenShock hub {} failed: {}", target, e)))
+        }).await;
+        result.unwrap_or_else(|_| Err(BackendError::Connection(format!("OpenShock hub did not confirm {} within {:?}", target, timeout))))
+    }
+
+    // Write out everything already queued, close the connection and stop reconnecting
+    pub async fn close(&self) -> Result<(), BackendError> {
+        let (done, closed) = oneshot::channel();
+        if self.outgoing.send(Outgoing::Close(done)).await.is_err() {
+            // The task is already gone
+            return Ok(());
+        }
+        closed.await.map_err(|_| BackendError::Connection("OpenShock hub task stopped before closing".to_string()))
+    }
+}
+
+// Hub URL next to the HTTP API, https becomes wss and http becomes ws
+pub fn hub_url(api_base_url: &str) -> String {
+    let ws_base = if let Some(host) = api_base_url.strip_prefix("https://") {
+        format!("wss://{}", host)
+    } else if let Some(host) = api_base_url.strip_prefix("http://") {
+        format!("ws://{}", host)
+    } else {
+        format!("wss://{}", api_base_url)
+    };
+    format!("{}/1/hubs/user", ws_base)
+}
+
+async fn run_hub(hub_url: String, auth_token: String, mut outgoing_rx: mpsc::Receiver<Outgoing>, connected: Arc<AtomicBool>) {
+    let mut backoff = RECONNECT_MIN;
+    // Confirmed frames that still have to go out on the next connection
+    let mut carried: Vec<Outgoing> = Vec::new();
+
+    loop {
+        log::debug!("Connecting to OpenShock hub: {}", hub_url);
+        let result = connect_and_run(&hub_url, &auth_token, &mut outgoing_rx, &mut carried, &connected, &mut backoff).await;
+        connected.store(false, Ordering::Relaxed);
+
+        match result {
+            Ok(Ended::Closed) => {
+                log::info!("Closed the OpenShock hub connection");
+                return;
+            },
+            Ok(Ended::ByServer) => log::warn!("OpenShock hub closed the connection"),
+            Err(e) => log::error!("OpenShock hub error: {}", e),
+        }
+
+        log::info!("Reconnecting to OpenShock hub in {:?}", backoff);
+        // Still answer a close while waiting, there is nothing to flush without a connection
+        let reconnect = time::sleep(backoff);
+        tokio::pin!(reconnect);
+        loop {
+            tokio::select! {
+                _ = &mut reconnect => break,
+                frame = outgoing_rx.recv() => match frame {
+                    Some(Outgoing::Close(done)) => {
+                        let _ = done.send(());
+                        return;
+                    },
+                    Some(frame @ Outgoing::Invoke { confirm: Some(_), .. }) => carried.push(frame),
+                    Some(Outgoing::Invoke { confirm: None, .. }) => {},
+                    None => return,
+                },
+            }
+        }
+        backoff = (backoff * 2).min(RECONNECT_MAX);
+    }
+}
+
+async fn connect_and_run(
+    hub_url: &str,
+    auth_token: &str,
+    outgoing_rx: &mut mpsc::Receiver<Outgoing>,
+    carried: &mut Vec<Outgoing>,
+    connected: &AtomicBool,
+    backoff: &mut Duration,
+) -> Result<Ended, Box<dyn std::error::Error + Send + Sync>> {
+    let mut request = hub_url.into_client_request()?;
+    request.headers_mut().insert("OpenShockToken", auth_token.parse()?);
+
+    let (ws_stream, _) = connect_async(request).await?;
+    let (mut write, mut read) = ws_stream.split();
+
+    // Handshake, the server answers with an empty record or an error
+    let handshake = format!("{}{}", json!({"protocol": "json", "version": 1}), RECORD_SEPARATOR);
+    write.send(Message::Text(handshake)).await?;
+    match time::timeout(SERVER_TIMEOUT, read.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => {
+            for record in split_records(&text) {
+                if let Some(error) = record.get("error").and_then(Value::as_str) {
+                    return Err(format!("Handshake rejected: {}", error).into());
+                }
+            }
+        },
+        Ok(Some(Ok(other))) => return Err(format!("Unexpected handshake response: {:?}", other).into()),
+        Ok(Some(Err(e))) => return Err(e.into()),
+        Ok(None) => return Err("Connection closed during handshake".into()),
+        Err(_) => return Err("Timed out waiting for handshake response".into()),
+    }
+
+    // Control frames queued for the previous connection are stale by now, confirmed frames and a close still go out
+    while let Ok(frame) = outgoing_rx.try_recv() {
+        if !matches!(frame, Outgoing::Invoke { confirm: None, .. }) {
+            carried.push(frame);
+        }
+    }
+
+    log::info!("Connected to OpenShock hub");
+    connected.store(true, Ordering::Relaxed);
+    *backoff = RECONNECT_MIN;
+
+    // Confirmed invocations waiting for their completion, by invocation ID
+    let mut pending: HashMap<String, Confirmation> = HashMap::new();
+    let mut next_invocation: u64 = 0;
+
+    for frame in std::mem::take(carried) {
+        if let Some(ended) = send_frame(&mut write, frame, &mut pending, &mut next_invocation).await? {
+            return Ok(ended);
+        }
+    }
+
+    let mut keep_alive = time::interval(KEEP_ALIVE_INTERVAL);
+    let mut last_received = Instant::now();
+
+    loop {
+        tokio::select! {
+            _ = keep_alive.tick() => {
+                if last_received.elapsed() > SERVER_TIMEOUT {
+                    return Err("Server timed out".into());
+                }
+                let ping = format!("{}{}", json!({"type": MESSAGE_PING}), RECORD_SEPARATOR);
+                write.send(Message::Text(ping)).await?;
+            },
+            frame = outgoing_rx.recv() => {
+                // The backend is gone, close politely
+                let frame = frame.unwrap_or_else(|| Outgoing::Close(oneshot::channel().0));
+                if let Some(ended) = send_frame(&mut write, frame, &mut pending, &mut next_invocation).await? {
+                    return Ok(ended);
+                }
+            },
+            message = read.next() => {
+                last_received = Instant::now();
+                match message {
+                    Some(Ok(Message::Text(text))) => {
+                        for record in split_records(&text) {
+                            match record.get("type").and_then(Value::as_u64) {
+                                Some(MESSAGE_CLOSE) => {
+                                    let error = record.get("error").and_then(Value::as_str).unwrap_or("no reason given");
+                                    return Err(format!("Hub closed the connection: {}", error).into());
+                                },
+                                Some(MESSAGE_COMPLETION) => complete(&record, &mut pending),
+                                _ => handle_hub_message(&record),
+                            }
+                        }
+                    },
+                    Some(Ok(Message::Ping(payload))) => write.send(Message::Pong(payload)).await?,
+                    Some(Ok(Message::Close(_))) | None => return Ok(Ended::ByServer),
+                    Some(Ok(_)) => {},
+                    Some(Err(e)) => return Err(e.into()),
+                }
+            },
+        }
+    }
+}
+
+// Write one queued frame, Some once the connection has been closed on request
+async fn send_frame<S>(write: &mut S, frame: Outgoing, pending: &mut HashMap<String, Confirmation>, next_invocation: &mut u64) -> Result<Option<Ended>, Box<dyn std::error::Error + Send + Sync>>
+where
+    S: Sink<Message> + Unpin,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    match frame {
+        Outgoing::Invoke { target, arguments, confirm } => {
+            let mut invocation = json!({
+                "type": MESSAGE_INVOCATION,
+                "target": target,
+                "arguments": arguments,
+            });
+            // Without an invocation ID the hub sends no completion
+            if let Some(confirm) = confirm {
+                *next_invocation += 1;
+                let id = next_invocation.to_string();
+                invocation["invocationId"] = json!(id);
+                pending.insert(id, confirm);
+            }
+            write.send(Message::Text(format!("{}{}", invocation, RECORD_SEPARATOR))).await?;
+            Ok(None)
+        },
+        Outgoing::Close(done) => {
+            write.close().await?;
+            let _ = done.send(());
+            Ok(Some(Ended::Closed))
+        },
+    }
+}
+
+// Answer whoever is waiting on this invocation
+fn complete(record: &Value, pending: &mut HashMap<String, Confirmation>) {
+    let id = record.get("invocationId").and_then(Value::as_str).unwrap_or_default();
+    match pending.remove(id) {
+        Some(confirm) => {
+            let result = match record.get("error").and_then(Value::as_str) {
+                Some(error) => Err(error.to_string()),
+                None => Ok(()),
+            };
+            let _ = confirm.send(result);
+        },
+        None => log::debug!("OpenShock hub completion for unknown invocation: {}", record),
+    }
+}
+
+// One WebSocket text message can carry several records
+fn split_records(text: &str) -> Vec<Value> {
+    text.split(RECORD_SEPARATOR)
+        .filter(|record| !record.trim().is_empty())
+        .filter_map(|record| match serde_json::from_str(record) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                log::error!("Invalid OpenShock hub message {}: {}", record, e);
+                None
+            }
+        })
+        .collect()
+}
+
+// Surface what the hub tells us about our devices
+fn handle_hub_message(message: &Value) {
+    match message.get("type").and_then(Value::as_u64) {
+        Some(MESSAGE_INVOCATION) => {},
+        Some(MESSAGE_PING) => return,
+        _ => {
+            log::debug!("OpenShock hub message: {}", message);
+            return;
+        }
+    }
+
+    let target = message.get("target").and_then(Value::as_str).unwrap_or_default();
+    let arguments = message.get("arguments").and_then(Value::as_array).cloned().unwrap_or_default();
+
+    match target {
+        "Welcome" => {
+            // The session ID tags where our commands came from in the OpenShock logs
+            log::info!("OpenShock hub session: {}", arguments.first().map(Value::to_string).unwrap_or_default());
+        },
+        "DeviceStatus" => {
+            let statuses = arguments.first().and_then(Value::as_array).cloned().unwrap_or_default();
+            for status in statuses {
+                let device = status.get("device").and_then(Value::as_str).unwrap_or("unknown device");
+                if status.get("online").and_then(Value::as_bool).unwrap_or(false) {
+                    log::info!("OpenShock device {} is online", device);
+                } else {
+                    log::warn!("OpenShock device {} is offline", device);
+                }
+            }
+        },
+        "DeviceUpdate" => {
+            log::info!("OpenShock device update: {:?}", arguments);
+        },
+        "Log" => {
+            log::debug!("OpenShock hub log: {:?}", arguments);
+        },
+        _ => log::debug!("OpenShock hub event {}: {:?}", target, arguments),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_tungstenite::tokio::{accept_async, accept_hdr_async};
+    use async_tungstenite::tungstenite::handshake::server::{Request, Response};
+    use tokio::net::TcpListener;
+
+    // Stand in for the OpenShock hub: does the handshake, completes every invocation with an ID
+    // and hands each record but pings to the test, None once the client closed the socket
+    async fn start_hub() -> (String, mpsc::UnboundedReceiver<Option<Value>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/1/hubs/user", listener.local_addr().unwrap());
+        let (records_tx, records) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            // Handshake request, answered with an empty record
+            ws.next().await.unwrap().unwrap();
+            ws.send(Message::Text(format!("{{}}{}", RECORD_SEPARATOR))).await.unwrap();
+
+            while let Some(Ok(message)) = ws.next().await {
+                match message {
+                    Message::Text(text) => {
+                        for record in split_records(&text) {
+                            if record["type"] == MESSAGE_PING {
+                                continue;
+                            }
+                            if let Some(id) = record.get("invocationId") {
+                                let completion = json!({"type": MESSAGE_COMPLETION, "invocationId": id});
+                                ws.send(Message::Text(format!("{}{}", completion, RECORD_SEPARATOR))).await.unwrap();
+                            }
+                            let _ = records_tx.send(Some(record));
+                        }
+                    },
+                    Message::Close(_) => break,
+                    _ => {},
+                }
+            }
+            let _ = records_tx.send(None);
+        });
+
+        (url, records)
+    }
+
+    async fn wait_connected(hub: &HubClient) {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !hub.is_connected() {
+            assert!(Instant::now() < deadline, "never connected to the stand in hub");
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn confirmed_stop_and_flush_on_close() {
+        let (url, mut records) = start_hub().await;
+        let hub = HubClient::start(url, "test-token".to_string());
+        wait_connected(&hub).await;
+
+        // The stop only returns once the hub completed it
+        let stop = json!([[{"id": "shocker", "type": 0, "intensity": 0, "duration": 300}], "ShockRS"]);
+        hub.invoke_confirmed("ControlV2", stop.clone(), Duration::from_secs(2)).await.unwrap();
+        let record = records.recv().await.unwrap().unwrap();
+        assert_eq!(record["type"], MESSAGE_INVOCATION);
+        assert_eq!(record["target"], "ControlV2");
+        assert_eq!(record["arguments"], stop);
+        assert!(record.get("invocationId").is_some());
+
+        // A frame queued right before close still reaches the hub, and the socket is closed after it
+        hub.invoke("ControlV2", json!([[], "ShockRS"])).unwrap();
+        hub.close().await.unwrap();
+        let record = records.recv().await.unwrap().unwrap();
+        assert_eq!(record["target"], "ControlV2");
+        assert!(record.get("invocationId").is_none());
+        assert!(records.recv().await.unwrap().is_none());
+
+        assert!(hub.invoke("ControlV2", json!([[], "ShockRS"])).is_err());
+    }
+
+    // The token goes in the upgrade request, and the client only counts as connected once the hub answered the handshake
+    // The callback's error type is tungstenite's, not ours
+    #[allow(clippy::result_large_err)]
+    #[tokio::test]
+    async fn handshake_comes_first() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/1/hubs/user", listener.local_addr().unwrap());
+        let hub = HubClient::start(url, "test-token".to_string());
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut token = None;
+        let mut ws = accept_hdr_async(stream, |request: &Request, response: Response| {
+            token = request.headers().get("OpenShockToken").map(|token| token.to_str().unwrap().to_string());
+            Ok(response)
+        }).await.unwrap();
+        assert_eq!(token.as_deref(), Some("test-token"));
+
+        let handshake = ws.next().await.unwrap().unwrap();
+        assert_eq!(handshake, Message::Text(format!("{{\"protocol\":\"json\",\"version\":1}}{}", RECORD_SEPARATOR)));
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(!hub.is_connected());
+
+        ws.send(Message::Text(format!("{{}}{}", RECORD_SEPARATOR))).await.unwrap();
+        wait_connected(&hub).await;
+    }
+
+    #[tokio::test]
+    async fn rejected_handshake_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/1/hubs/user", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            ws.next().await.unwrap().unwrap();
+            let error = json!({"error": "Requested protocol 'json' is not available."});
+            ws.send(Message::Text(format!("{}{}", error, RECORD_SEPARATOR))).await.unwrap();
+            // Hold the socket open so only the error can end the handshake
+            time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let (_outgoing, mut outgoing_rx) = mpsc::channel(1);
+        let connected = AtomicBool::new(false);
+        let mut backoff = RECONNECT_MAX;
+        let result = connect_and_run(&url, "test-token", &mut outgoing_rx, &mut Vec::new(), &connected, &mut backoff).await;
+        assert!(result.err().unwrap().to_string().starts_with("Handshake rejected"));
+        assert!(!connected.load(Ordering::Relaxed));
+        assert_eq!(backoff, RECONNECT_MAX);
+    }
+
+    // Every frame we send is one record, the hub may pack several into one message
+    #[tokio::test]
+    async fn records_are_framed_by_the_separator() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/1/hubs/user", listener.local_addr().unwrap());
+        let hub = HubClient::start(url, "test-token".to_string());
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = accept_async(stream).await.unwrap();
+        ws.next().await.unwrap().unwrap();
+        ws.send(Message::Text(format!("{{}}{}", RECORD_SEPARATOR))).await.unwrap();
+        wait_connected(&hub).await;
+
+        let stop = tokio::spawn(async move { hub.invoke_confirmed("ControlV2", json!([[], "ShockRS"]), Duration::from_secs(2)).await });
+        // Keep-alive pings are framed the same way, the first one goes out right after the handshake
+        let invocation = loop {
+            let text = match ws.next().await.unwrap().unwrap() {
+                Message::Text(text) => text,
+                other => panic!("expected a text frame, got {:?}", other),
+            };
+            assert!(text.ends_with(RECORD_SEPARATOR));
+            assert_eq!(text.matches(RECORD_SEPARATOR).count(), 1);
+            let record: Value = serde_json::from_str(text.trim_end_matches(RECORD_SEPARATOR)).unwrap();
+            if record["type"] == MESSAGE_INVOCATION {
+                break record;
+            }
+        };
+
+        // A ping and the completion in one message, the completion is still found
+        let completion = json!({"type": MESSAGE_COMPLETION, "invocationId": invocation["invocationId"]});
+        ws.send(Message::Text(format!("{}{}{}{}", json!({"type": MESSAGE_PING}), RECORD_SEPARATOR, completion, RECORD_SEPARATOR))).await.unwrap();
+        stop.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn split_records_skips_empty_and_invalid_records() {
+        let text = format!("{{\"type\":6}}{sep}{{\"type\":3,\"invocationId\":\"1\"}}{sep}", sep = RECORD_SEPARATOR);
+        assert_eq!(split_records(&text), vec![json!({"type": 6}), json!({"type": 3, "invocationId": "1"})]);
+        assert!(split_records("").is_empty());
+        assert_eq!(split_records(&format!("not json{sep} {sep}{{}}{sep}", sep = RECORD_SEPARATOR)), vec![json!({})]);
+    }
+
+    // A frame that can't be queued is reported instead of silently dropped
+    #[tokio::test]
+    async fn full_or_closed_queue_is_an_error() {
+        let (outgoing, outgoing_rx) = mpsc::channel(1);
+        let hub = HubClient { outgoing, connected: Arc::new(AtomicBool::new(true)) };
+
+        hub.invoke("ControlV2", json!([])).unwrap();
+        assert!(matches!(hub.invoke("ControlV2", json!([])), Err(BackendError::Connection(_))));
+
+        drop(outgoing_rx);
+        assert!(matches!(hub.invoke("ControlV2", json!([])), Err(BackendError::Connection(_))));
+    }
+}
@@ -1,93 +1,144 @@
-use crate::{osc::touchpoints::{self, CommandState},openshock_legacy::websocket::WebSocketClient};
+use crate::{osc::touchpoints,openshock_legacy::websocket::WebSocketClient};
+use crate::backend::{BackendError, BackendHealth, Sent, ShockerBackend, ShockerCommand, ShockerInfo, METHOD_SHOCK, METHOD_VIBRATE};
+use async_trait::async_trait;
 use chrono::Utc;
-use tokio::sync::Mutex;
 use std::sync::Arc;
-use tokio::time::{Instant,self,Duration};
 use std::collections::HashMap;
 use serde_json::json;
+use tokio::time::Duration;
 
-// For reference
-/*pub struct CommandState {
-    pub id: String,
-    pub duration: u64,
-    pub intensity: f32,
-    pub last_issued: Instant,
-    pub expiry: Instant,
-} */
-
-pub async fn handler(websocket_url: String,commandmap: Arc<Mutex<HashMap<String, CommandState>>>){
-
-    log::debug!("Legacy Touchpoint Handler");
-
-    let websocket_url = format!("ws://{}:8080/ws",websocket_url);
-
-    log::debug!("WebSocket URL: {}",websocket_url);
-
-    let touchpoint_config = &touchpoints::get_config().touchpoints;
-
-    // Initialize the WebSocket client and store it in a Mutex 
-    let ws_client = match WebSocketClient::get_or_init_websocket_client(&websocket_url).await {
-        Ok(client) => client,
-        Err(e) => {
-            log::error!("Failed to create WebSocket client: {}", e);
-            return;
-        },
-    };
-
-        // WebSocket client mutex
-        let ws_client_mutex = Arc::new(Mutex::new(ws_client));
-
-    // Command loop duration
-    //let mut interval = time::interval(Duration::from_millis(50));
-    let interval = Duration::from_millis(150);
-    loop {
-        let now = Instant::now();
-    
-        // First, clone the command map so we can iterate without holding the lock.
-        // This step assumes that the command map doesn't get too large to clone each iteration.
-        // If the command map is very large, this might not be the most efficient approach.
-        let cloned_commandmap = {
-            let commandmap_lock = commandmap.lock().await;
-            commandmap_lock.clone() // We clone here to avoid holding the lock while sending messages.
-        };
+// Every frame runs this long, the router resends while the command is active
+const FRAME_DURATION_MS: u64 = 50;
 
+// Legacy firmware takes u16 shocker IDs over a local WebSocket
+pub struct LegacyBackend {
+    ws_client: Arc<WebSocketClient>,
+}
+
+impl LegacyBackend {
+    pub async fn connect(api_endpoint: &str) -> Result<LegacyBackend, BackendError> {
+        log::debug!("Legacy Touchpoint Handler");
+
+        let websocket_url = format!("ws://{}:8080/ws",api_endpoint);
+
+        log::debug!("WebSocket URL: {}",websocket_url);
+
+        // Initialize the WebSocket client
+        let ws_client = WebSocketClient::get_or_init_websocket_client(&websocket_url).await
+            .map_err(|e| BackendError::Connection(format!("Failed to create WebSocket client: {}", e)))?;
+
+        Ok(LegacyBackend { ws_client })
+    }
+
+    async fn send_batch(&self, method: u8, intensity: u8, duration: u64, ids: Vec<u16>) -> Result<(), BackendError> {
+        let json_payload = json!({
+            "method": method,
+            "intensity": intensity,
+            "duration": duration,
+            "ids": ids,
+            "timestamp": Utc::now().timestamp_millis(),
+        }).to_string();
+
+        log::debug!("Sending JSON payload: {}", json_payload);
+        self.ws_client.send(json_payload).await
+            .map_err(|e| BackendError::Connection(e.to_string()))
+    }
+
+    // Legacy has no listing API, so the IDs in touchpoints.toml are all we know about
+    fn configured_ids() -> Vec<u16> {
+        let mut ids: Vec<u16> = touchpoints::get_config().all_devices()
+            .flat_map(|device| device.ids.iter())
+            .filter_map(|id| id.parse::<u16>().ok())
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+}
+
+#[async_trait]
+impl ShockerBackend for LegacyBackend {
+    fn name(&self) -> &'static str {
+        "Legacy"
+    }
+
+    async fn send_control(&self, commands: &[ShockerCommand]) -> Result<Vec<Sent>, BackendError> {
         let mut batch_commands: HashMap<(u8, u8), Vec<u16>> = HashMap::new();
 
-        // Iterate over cloned command map and send commands for non-expired IDs
-
-        for (key, command_state) in cloned_commandmap.iter() {
-            if command_state.expiry > now && command_state.intensity > 0.0 {
-                // Parse and prepare your keys
-                let (id_str, method_str) = key.split_once('_').expect("Invalid key format");
-                let id = id_str.parse::<u16>().expect("Invalid ID format");
-                let method = method_str.parse::<u8>().expect("Invalid method format");
-                let intensity = (command_state.intensity * 100.0).round() as u8;
-        
-                // Group commands by method and intensity
-                batch_commands
-                    .entry((method, intensity))
-                    .or_default()
-                    .push(id);
-            }
+        for command in commands {
+            // Anything that isn't a u16 belongs to another backend
+            let id = match command.id.parse::<u16>() {
+                Ok(id) => id,
+                Err(_) => {
+                    log::debug!("Legacy skipping non legacy shocker ID: {}", command.id);
+                    continue;
+                }
+            };
+            let intensity = (command.intensity * 100.0).round() as u8;
+
+            // Group commands by method and intensity
+            batch_commands
+                .entry((command.method, intensity))
+                .or_default()
+                .push(id);
         }
 
+        let mut sent = Vec::new();
         for ((method, intensity), ids) in batch_commands {
-            let json_payload = json!({
-                "method": method,
-                "intensity": intensity,
-                "duration": 50, // fixed duration for all commands
-                "ids": ids,
-                "timestamp": Utc::now().timestamp_millis(),
-            }).to_string();
-        
-            log::debug!("Sending JSON payload: {}", json_payload);
-            // Acquire lock and send
-            let client_lock = ws_client_mutex.lock().await;
-            client_lock.send(json_payload).await.unwrap(); // Properly handle errors
+            // fixed duration for all commands, the router resends while the command is active
+            self.send_batch(method, intensity, FRAME_DURATION_MS, ids.clone()).await?;
+            sent.extend(ids.iter().map(|id| Sent::new(&id.to_string(), method, Duration::from_millis(FRAME_DURATION_MS))));
         }
-    
-        // Sleep until the next loop iteration to maintain the loop interval
-        time::sleep(interval).await;
+        Ok(sent)
     }
 
+    fn hardware_duration(&self, command: &ShockerCommand) -> Option<Duration> {
+        command.id.parse::<u16>().ok().map(|_| Duration::from_millis(FRAME_DURATION_MS))
+    }
+
+    async fn stop_all(&self) -> Result<(), BackendError> {
+        let ids = LegacyBackend::configured_ids();
+        if ids.is_empty() {
+            return Ok(());
+        }
+        // Legacy has no stop command, a zero intensity frame replaces whatever is running
+        for method in [METHOD_SHOCK, METHOD_VIBRATE] {
+            self.send_batch(method, 0, 0, ids.clone()).await?;
+        }
+        Ok(())
+    }
+
+    async fn stop(&self, ids: &[String]) -> Result<(), BackendError> {
+        let ids: Vec<u16> = LegacyBackend::configured_ids().into_iter()
+            .filter(|configured| ids.iter().any(|id| id.parse::<u16>().ok() == Some(*configured)))
+            .collect();
+        if ids.is_empty() {
+            return Ok(());
+        }
+        for method in [METHOD_SHOCK, METHOD_VIBRATE] {
+            self.send_batch(method, 0, 0, ids.clone()).await?;
+        }
+        Ok(())
+    }
+
+    async fn list_shockers(&self) -> Result<Vec<ShockerInfo>, BackendError> {
+        Ok(LegacyBackend::configured_ids().into_iter().map(|id| ShockerInfo {
+            id: id.to_string(),
+            name: format!("Legacy {}", id),
+            paused: false,
+        }).collect())
+    }
+
+    async fn health(&self) -> BackendHealth {
+        if self.ws_client.is_connected().await {
+            BackendHealth::Connected
+        } else {
+            BackendHealth::Disconnected("WebSocket is not connected".to_string())
+        }
+    }
+
+    async fn close(&self) -> Result<(), BackendError> {
+        self.ws_client.close().await
+            .map_err(|e| BackendError::Connection(e.to_string()))
+    }
 }
@@ -95,6 +95,10 @@ impl WebSocketClient {
     }
     
 
+    pub async fn is_connected(&self) -> bool {
+        self.ws_stream.lock().await.is_some()
+    }
+
     pub async fn receive(&self) -> Option<Result<async_tungstenite::tungstenite::Message, async_tungstenite::tungstenite::Error>> {
         let mut ws_stream = self.ws_stream.lock().await;
         if let Some(stream) = ws_stream.as_mut() {
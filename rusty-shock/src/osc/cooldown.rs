@@ -0,0 +1,203 @@
+use crate::osc::touchpoints::Device;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+// What happens to a trigger that arrives while its touchpoint or shocker is cooling down
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CooldownPolicy {
+    // Drop it
+    #[default]
+    Ignore,
+    // Hold the newest one and send it once the cooldown is over
+    Queue,
+}
+
+// One touchpoint firing on a set of shockers
+#[derive(Debug, Clone)]
+pub struct Trigger {
+    // The address VRChat sent, a wildcard touchpoint cools down per matching contact
+    pub address: String,
+    // Command map keys, "{id}_{method}"
+    pub shocker_ids: Vec<String>,
+    pub intensity: f32,
+    pub duration: u64,
+    // How long the command stays active in the command map
+    pub active_for: Duration,
+}
+
+struct QueuedTrigger {
+    trigger: Trigger,
+    ready_at: Instant,
+}
+
+// When each touchpoint and shocker comes off cooldown
+pub struct Cooldowns {
+    touchpoints: HashMap<String, Instant>,
+    shockers: HashMap<String, Instant>,
+    // Trigger address -> newest held back trigger
+    queued: HashMap<String, QueuedTrigger>,
+}
+
+pub static COOLDOWNS: Lazy<Mutex<Cooldowns>> = Lazy::new(|| Mutex::new(Cooldowns {
+    touchpoints: HashMap::new(),
+    shockers: HashMap::new(),
+    queued: HashMap::new(),
+}));
+
+impl Cooldowns {
+    // The command map keys the trigger may fire on right now and starts their cooldowns
+    // Anything still cooling down is dropped or queued according to the touchpoint's cooldown_policy
+    // shocker_cooldowns is shocker ID -> milliseconds, from the touchpoints file the trigger's set came from
+    pub fn admit(&mut self, device: &Device, trigger: Trigger, shocker_cooldowns: &HashMap<String, u64>, now: Instant) -> Vec<String> {
+        // Releasing a contact must always get through, and it doesn't count as a trigger
+        if trigger.intensity <= 0.0 {
+            return trigger.shocker_ids;
+        }
+
+        if let Some(&until) = self.touchpoints.get(&trigger.address).filter(|&&until| until > now) {
+            log::debug!("Touchpoint {} cooling down, {} ms left", trigger.address, (until - now).as_millis());
+            self.hold_back(device, trigger, until);
+            return Vec::new();
+        }
+
+        let mut allowed = Vec::new();
+        let mut blocked = Vec::new();
+        let mut blocked_until = now;
+        for shocker_id in trigger.shocker_ids.iter() {
+            match self.shockers.get(shocker_key(shocker_id)).filter(|&&until| until > now) {
+                Some(&until) => {
+                    log::debug!("Shocker {} cooling down, {} ms left", shocker_key(shocker_id), (until - now).as_millis());
+                    blocked.push(shocker_id.clone());
+                    blocked_until = blocked_until.max(until);
+                },
+                None => allowed.push(shocker_id.clone()),
+            }
+        }
+
+        if !allowed.is_empty() {
+            if device.cooldown > 0 {
+                self.touchpoints.insert(trigger.address.clone(), now + Duration::from_millis(device.cooldown));
+            }
+            for shocker_id in &allowed {
+                let id = shocker_key(shocker_id);
+                if let Some(&cooldown) = shocker_cooldowns.get(id).filter(|&&cooldown| cooldown > 0) {
+                    self.shockers.insert(id.to_string(), now + Duration::from_millis(cooldown));
+                }
+            }
+        }
+
+        if blocked.is_empty() {
+            // Fired in full, an older queued trigger would only repeat it
+            self.queued.remove(&trigger.address);
+        } else {
+            self.hold_back(device, Trigger { shocker_ids: blocked, ..trigger }, blocked_until);
+        }
+
+        allowed
+    }
+
+    // Queued triggers whose cooldown is over, they still have to go through admit again
+    pub fn take_ready(&mut self, now: Instant) -> Vec<Trigger> {
+        let ready: Vec<String> = self.queued.iter()
+            .filter(|(_, queued)| queued.ready_at <= now)
+            .map(|(address, _)| address.clone())
+            .collect();
+        ready.into_iter()
+            .filter_map(|address| self.queued.remove(&address))
+            .map(|queued| queued.trigger)
+            .collect()
+    }
+
+    // Drop the queued triggers of addresses that no longer belong to the same touchpoint
+    // Cooldowns stay and run out on their own, otherwise switching avatars away and back would skip them
+    pub fn retain_queued(&mut self, keep: impl Fn(&str) -> bool) {
+        self.queued.retain(|address, _| keep(address));
+    }
+
+    pub fn clear_queue(&mut self) {
+        if !self.queued.is_empty() {
+            log::debug!("Dropping {} queued trigger(s)", self.queued.len());
+            self.queued.clear();
+        }
+    }
+
+    fn hold_back(&mut self, device: &Device, trigger: Trigger, ready_at: Instant) {
+        if device.cooldown_policy == CooldownPolicy::Queue {
+            log::debug!("Queued trigger for {} at intensity {}", trigger.address, trigger.intensity);
+            self.queued.insert(trigger.address.clone(), QueuedTrigger { trigger, ready_at });
+        }
+    }
+}
+
+// Shocker cooldowns are per shocker, whatever the method
+fn shocker_key(shocker_id: &str) -> &str {
+    shocker_id.rsplit_once('_').map(|(id, _)| id).unwrap_or(shocker_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cooldowns() -> Cooldowns {
+        Cooldowns { touchpoints: HashMap::new(), shockers: HashMap::new(), queued: HashMap::new() }
+    }
+
+    fn trigger(address: &str) -> Trigger {
+        Trigger {
+            address: address.to_string(),
+            shocker_ids: vec!["cooldown-test_2".to_string()],
+            intensity: 0.5,
+            duration: 50,
+            active_for: Duration::from_millis(50),
+        }
+    }
+
+    #[test]
+    fn wildcard_touchpoint_cools_down_per_address() {
+        let device: Device = toml::from_str(r#"
+            address = "Hand*"
+            method = [2]
+            intensity = 1.0
+            duration = 100
+            ids = ["cooldown-test"]
+            cooldown = 1000
+        "#).unwrap();
+        let mut cooldowns = cooldowns();
+        let now = Instant::now();
+
+        assert_eq!(cooldowns.admit(&device, trigger("/avatar/parameters/HandLeft"), &HashMap::new(), now).len(), 1);
+        // The other hand is its own contact
+        assert_eq!(cooldowns.admit(&device, trigger("/avatar/parameters/HandRight"), &HashMap::new(), now).len(), 1);
+        assert!(cooldowns.admit(&device, trigger("/avatar/parameters/HandLeft"), &HashMap::new(), now + Duration::from_millis(500)).is_empty());
+    }
+
+    // Switching to an avatar without the touchpoint and back doesn't reset its cooldown
+    #[test]
+    fn avatar_switch_keeps_the_cooldown() {
+        let device: Device = toml::from_str(r#"
+            address = "tail"
+            method = [2]
+            intensity = 1.0
+            duration = 100
+            ids = ["cooldown-test"]
+            cooldown = 1000
+            cooldown_policy = "queue"
+        "#).unwrap();
+        let mut cooldowns = cooldowns();
+        let now = Instant::now();
+
+        assert_eq!(cooldowns.admit(&device, trigger("/avatar/parameters/tail"), &HashMap::new(), now).len(), 1);
+        // Queued behind the cooldown
+        assert!(cooldowns.admit(&device, trigger("/avatar/parameters/tail"), &HashMap::new(), now + Duration::from_millis(100)).is_empty());
+
+        cooldowns.retain_queued(|_| false);
+        let back = now + Duration::from_millis(200);
+        assert!(cooldowns.admit(&device, trigger("/avatar/parameters/tail"), &HashMap::new(), back).is_empty());
+        // The queued one from before the switch is gone, only the new one is left
+        assert_eq!(cooldowns.take_ready(now + Duration::from_millis(1000)).len(), 1);
+    }
+}
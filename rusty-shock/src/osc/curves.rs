@@ -0,0 +1,203 @@
+use serde::{Deserialize, Deserializer};
+
+// How a contact value (0.0 - 1.0) turns into intensity before the touchpoint's intensity scales it
+// In touchpoints.toml: curve = { type = "exponential", strength = 2.0, dead_zone = 0.1, floor = 0.2 }
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Curve {
+    #[serde(rename = "type")]
+    pub kind: CurveKind,
+    // Exponent for exponential (default 2), steepness for logarithmic (default 9)
+    pub strength: Option<f32>,
+    // [contact value, output] pairs
+    // stepped: the output of the highest threshold reached, custom: interpolated between the points
+    pub points: Vec<[f32; 2]>,
+    // Contact values at or below this do nothing
+    #[serde(deserialize_with = "dead_zone")]
+    pub dead_zone: f32,
+    // Lowest non zero output, the rest of the curve is squeezed between floor and 1.0
+    #[serde(deserialize_with = "floor")]
+    pub floor: f32,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CurveKind {
+    #[default]
+    Linear,
+    Exponential,
+    Logarithmic,
+    Stepped,
+    Custom,
+}
+
+impl Default for Curve {
+    fn default() -> Self {
+        Curve {
+            kind: CurveKind::Linear,
+            strength: None,
+            points: Vec::new(),
+            dead_zone: 0.0,
+            floor: 0.0,
+        }
+    }
+}
+
+// Outside 0.0 - 1.0 either can push the output past full intensity or turn it into NaN, so they are clamped when touchpoints load
+fn dead_zone<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    let dead_zone = unit_interval(deserializer, "dead_zone")?;
+    if dead_zone >= 1.0 {
+        log::warn!("Curve dead_zone {} covers every contact value, the touchpoint will never fire", dead_zone);
+    }
+    Ok(dead_zone)
+}
+
+fn floor<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    unit_interval(deserializer, "floor")
+}
+
+fn unit_interval<'de, D: Deserializer<'de>>(deserializer: D, name: &str) -> Result<f32, D::Error> {
+    let value = f32::deserialize(deserializer)?;
+    let clamped = if value.is_nan() { 0.0 } else { value.clamp(0.0, 1.0) };
+    if clamped != value {
+        log::warn!("Curve {} {} is outside 0.0 - 1.0, using {}", name, value, clamped);
+    }
+    Ok(clamped)
+}
+
+impl Curve {
+    pub fn apply(&self, value: f32) -> f32 {
+        let value = if value.is_nan() { 0.0 } else { value.clamp(0.0, 1.0) };
+        if value <= self.dead_zone {
+            return 0.0;
+        }
+
+        // What's left above the dead zone stretched back to 0.0 - 1.0
+        let scaled = if self.dead_zone > 0.0 { (value - self.dead_zone) / (1.0 - self.dead_zone) } else { value };
+
+        let output = match self.kind {
+            CurveKind::Linear => scaled,
+            CurveKind::Exponential => scaled.powf(self.strength.unwrap_or(2.0).max(f32::EPSILON)),
+            CurveKind::Logarithmic => {
+                let steepness = self.strength.unwrap_or(9.0).max(f32::EPSILON);
+                (1.0 + steepness * scaled).ln() / (1.0 + steepness).ln()
+            },
+            // Points are written against the contact value people see in VRChat, not the scaled one
+            CurveKind::Stepped => self.step(value),
+            CurveKind::Custom => self.interpolate(value),
+        }.clamp(0.0, 1.0);
+
+        if output <= 0.0 {
+            return 0.0;
+        }
+        self.floor + output * (1.0 - self.floor)
+    }
+
+    fn sorted_points(&self) -> Vec<[f32; 2]> {
+        let mut points = self.points.clone();
+        points.sort_by(|a, b| a[0].total_cmp(&b[0]));
+        points
+    }
+
+    fn step(&self, value: f32) -> f32 {
+        self.sorted_points().iter()
+            .take_while(|[threshold, _]| value >= *threshold)
+            .last()
+            .map(|[_, output]| *output)
+            .unwrap_or(0.0)
+    }
+
+    fn interpolate(&self, value: f32) -> f32 {
+        let points = self.sorted_points();
+        let (first, last) = match (points.first(), points.last()) {
+            (Some(first), Some(last)) => (first, last),
+            // No points behaves like linear
+            _ => return value,
+        };
+        if value <= first[0] {
+            return first[1];
+        }
+        if value >= last[0] {
+            return last[1];
+        }
+
+        points.windows(2)
+            .find(|pair| value <= pair[1][0])
+            .map(|pair| {
+                let ([x0, y0], [x1, y1]) = (pair[0], pair[1]);
+                if x1 == x0 { y1 } else { y0 + (value - x0) / (x1 - x0) * (y1 - y0) }
+            })
+            .unwrap_or(last[1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(toml: &str) -> Curve {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn linear_with_dead_zone_and_floor() {
+        let curve = load("dead_zone = 0.2\nfloor = 0.1");
+        assert_eq!(curve.apply(0.0), 0.0);
+        assert_eq!(curve.apply(0.2), 0.0);
+        // Halfway through what's left of the dead zone, squeezed above the floor
+        assert!(close(curve.apply(0.6), 0.1 + 0.5 * 0.9));
+        assert!(close(curve.apply(1.0), 1.0));
+        // Out of range and NaN contacts never go past full or below nothing
+        assert!(close(curve.apply(3.0), 1.0));
+        assert_eq!(curve.apply(f32::NAN), 0.0);
+    }
+
+    #[test]
+    fn exponential_with_dead_zone_and_floor() {
+        let curve = load("type = \"exponential\"\nstrength = 3.0\ndead_zone = 0.5\nfloor = 0.2");
+        assert_eq!(curve.apply(0.5), 0.0);
+        assert!(close(curve.apply(0.75), 0.2 + 0.125 * 0.8));
+        assert!(close(curve.apply(1.0), 1.0));
+    }
+
+    // Stepped and custom points go by the contact value in VRChat, the dead zone only cuts off the low end
+    #[test]
+    fn stepped_with_dead_zone_and_floor() {
+        let curve = load("type = \"stepped\"\npoints = [[0.8, 1.0], [0.3, 0.5]]\ndead_zone = 0.1\nfloor = 0.2");
+        assert_eq!(curve.apply(0.1), 0.0);
+        // Above the dead zone but below the first step
+        assert_eq!(curve.apply(0.2), 0.0);
+        assert!(close(curve.apply(0.3), 0.2 + 0.5 * 0.8));
+        assert!(close(curve.apply(0.79), 0.6));
+        assert!(close(curve.apply(0.8), 1.0));
+    }
+
+    #[test]
+    fn custom_with_dead_zone_and_floor() {
+        let curve = load("type = \"custom\"\npoints = [[0.2, 0.0], [0.6, 0.4], [1.0, 1.0]]\ndead_zone = 0.3\nfloor = 0.5");
+        assert_eq!(curve.apply(0.3), 0.0);
+        assert!(close(curve.apply(0.4), 0.5 + 0.2 * 0.5));
+        assert!(close(curve.apply(0.8), 0.5 + 0.7 * 0.5));
+        assert!(close(curve.apply(1.0), 1.0));
+
+        // No points behaves like linear
+        let curve = load("type = \"custom\"");
+        assert!(close(curve.apply(0.25), 0.25));
+    }
+
+    #[test]
+    fn dead_zone_and_floor_are_clamped_when_loaded() {
+        let curve = load("floor = 1.5\ndead_zone = -0.5");
+        assert_eq!((curve.dead_zone, curve.floor), (0.0, 1.0));
+        assert!(close(curve.apply(0.3), 1.0));
+
+        let curve = load("floor = -0.5\ndead_zone = 2.0");
+        assert_eq!((curve.dead_zone, curve.floor), (1.0, 0.0));
+        assert_eq!(curve.apply(1.0), 0.0);
+        assert!(curve.apply(f32::INFINITY).is_finite());
+    }
+}
@@ -0,0 +1,219 @@
+use crate::backend::{BackendHealth, ShockerBackend, ShockerCommand, METHOD_SHOCK};
+use crate::config;
+use crate::osc::osc;
+use crate::safety::estop;
+use futures::future::join_all;
+use once_cell::sync::Lazy;
+use rosc::{OscMessage, OscPacket, OscType};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::time::{self, Duration, Instant};
+
+// Backend health can mean an HTTP request, so it is checked far less often than the rest
+const HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+// Everything is resent now and then, VRChat resets parameters when an avatar is (re)loaded
+const RESEND_INTERVAL: Duration = Duration::from_secs(5);
+
+// What the backend router last sent
+struct Dispatched {
+    last_intensity: f32,
+    last_method: u8,
+    shock_active: bool,
+}
+
+static DISPATCHED: Lazy<Mutex<Dispatched>> = Lazy::new(|| Mutex::new(Dispatched {
+    last_intensity: 0.0,
+    last_method: 0,
+    shock_active: false,
+}));
+
+// Called by the backend router every pass with what actually went to the backends
+pub fn record_dispatch(commands: &[ShockerCommand]) {
+    let mut dispatched = DISPATCHED.lock().expect("Feedback lock poisoned");
+    dispatched.shock_active = commands.iter().any(|command| command.method == METHOD_SHOCK);
+    if let Some(strongest) = commands.iter().max_by(|a, b| a.intensity.total_cmp(&b.intensity)) {
+        dispatched.last_intensity = strongest.intensity;
+        dispatched.last_method = strongest.method;
+    }
+}
+
+// Send the status parameters to the OSC client (osc.ip_address:osc.send_port) so avatars can show them
+pub async fn start_feedback(backends: Vec<Arc<dyn ShockerBackend>>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let feedback_config = &config::get_config().feedback;
+    let mut send_addr = osc::send_addr()?;
+    let bind_addr: SocketAddr = if send_addr.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    log::info!("Sending feedback parameters to {}", send_addr);
+
+    let mut interval = time::interval(Duration::from_millis(feedback_config.interval_ms.max(10)));
+    let mut connected = false;
+    let mut last_health_check: Option<Instant> = None;
+    let mut outbox = Outbox::new();
+
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+
+        // Can move when VRChat is found (or lost) over OSCQuery
+        let target = osc::send_addr()?;
+        let moved = target != send_addr;
+        if moved {
+            log::info!("Sending feedback parameters to {}", target);
+            send_addr = target;
+        }
+
+        if last_health_check.is_none_or(|checked| now.duration_since(checked) >= HEALTH_INTERVAL) {
+            connected = backends_connected(&backends).await;
+            last_health_check = Some(now);
+        }
+
+        let parameters = {
+            let dispatched = DISPATCHED.lock().expect("Feedback lock poisoned");
+            [
+                (&feedback_config.connected_parameter, OscType::Bool(connected)),
+                (&feedback_config.armed_parameter, OscType::Bool(!estop::is_engaged())),
+                (&feedback_config.intensity_parameter, OscType::Float(dispatched.last_intensity)),
+                (&feedback_config.method_parameter, OscType::Int(dispatched.last_method as i32)),
+                (&feedback_config.shock_active_parameter, OscType::Bool(dispatched.shock_active)),
+            ]
+        };
+
+        for (address, value) in outbox.due(&parameters, now, moved) {
+            let packet = OscPacket::Message(OscMessage { addr: address.clone(), args: vec![value.clone()] });
+            match rosc::encoder::encode(&packet) {
+                Ok(bytes) => {
+                    if let Err(e) = socket.send_to(&bytes, send_addr).await {
+                        log::error!("Failed to send feedback parameter {}: {}", address, e);
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    log::error!("Failed to encode feedback parameter {}: {}", address, e);
+                    continue;
+                }
+            }
+            outbox.sent(address, value);
+        }
+    }
+}
+
+// What VRChat was last sent, parameters only go out when they change or on the periodic resend
+struct Outbox {
+    sent: Vec<(String, OscType)>,
+    last_resend: Option<Instant>,
+}
+
+impl Outbox {
+    fn new() -> Outbox {
+        Outbox { sent: Vec::new(), last_resend: None }
+    }
+
+    // The parameters to send this tick, everything when moved is true (EX: VRChat found at a new address)
+    fn due(&mut self, parameters: &[(&String, OscType)], now: Instant, moved: bool) -> Vec<(String, OscType)> {
+        let resend = moved || self.last_resend.is_none_or(|resent| now.duration_since(resent) >= RESEND_INTERVAL);
+        if resend {
+            self.last_resend = Some(now);
+        }
+
+        parameters.iter()
+            // A blank name turns that parameter off
+            .filter(|(address, _)| !address.is_empty())
+            .filter(|(address, value)| {
+                let changed = !self.sent.iter().any(|(sent_address, sent_value)| sent_address == *address && sent_value == value);
+                if changed {
+                    log::trace!("Feedback {} = {:?}", address, value);
+                }
+                changed || resend
+            })
+            .map(|(address, value)| ((*address).clone(), value.clone()))
+            .collect()
+    }
+
+    // Only what actually went out counts, a failed send is tried again next tick
+    fn sent(&mut self, address: String, value: OscType) {
+        self.sent.retain(|(sent_address, _)| *sent_address != address);
+        self.sent.push((address, value));
+    }
+}
+
+// True only when there is at least one backend and every one of them is healthy
+async fn backends_connected(backends: &[Arc<dyn ShockerBackend>]) -> bool {
+    let health = join_all(backends.iter().map(|backend| backend.health())).await;
+    let mut connected = !backends.is_empty();
+    for (backend, health) in backends.iter().zip(health) {
+        if let BackendHealth::Disconnected(reason) = health {
+            log::debug!("{} backend is disconnected: {}", backend.name(), reason);
+            connected = false;
+        }
+    }
+    connected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_all(outbox: &mut Outbox, parameters: &[(&String, OscType)], now: Instant, moved: bool) -> Vec<String> {
+        let due = outbox.due(parameters, now, moved);
+        let addresses = due.iter().map(|(address, _)| address.clone()).collect();
+        for (address, value) in due {
+            outbox.sent(address, value);
+        }
+        addresses
+    }
+
+    #[test]
+    fn only_changes_go_out_between_resends() {
+        let (armed, intensity, off) = ("ShockRS_Armed".to_string(), "ShockRS_Intensity".to_string(), String::new());
+        let mut outbox = Outbox::new();
+        let now = Instant::now();
+
+        // Everything on the first tick, except the parameter turned off with a blank name
+        let parameters = [(&armed, OscType::Bool(true)), (&intensity, OscType::Float(0.0)), (&off, OscType::Int(1))];
+        assert_eq!(send_all(&mut outbox, &parameters, now, false), vec![armed.clone(), intensity.clone()]);
+
+        let later = now + Duration::from_secs(1);
+        assert!(send_all(&mut outbox, &parameters, later, false).is_empty());
+        let parameters = [(&armed, OscType::Bool(true)), (&intensity, OscType::Float(0.5))];
+        assert_eq!(send_all(&mut outbox, &parameters, later, false), vec![intensity.clone()]);
+
+        // Unchanged, but the resend interval is up
+        assert_eq!(send_all(&mut outbox, &parameters, now + RESEND_INTERVAL, false).len(), 2);
+    }
+
+    #[test]
+    fn moving_or_failing_to_send_sends_again() {
+        let armed = "ShockRS_Armed".to_string();
+        let mut outbox = Outbox::new();
+        let now = Instant::now();
+        let parameters = [(&armed, OscType::Bool(true))];
+
+        // Due but never marked as sent, so it is still a change next tick
+        assert_eq!(outbox.due(&parameters, now, false).len(), 1);
+        assert_eq!(outbox.due(&parameters, now + Duration::from_millis(100), false).len(), 1);
+        send_all(&mut outbox, &parameters, now + Duration::from_millis(200), false);
+
+        // VRChat moved, it hasn't seen anything yet
+        assert_eq!(outbox.due(&parameters, now + Duration::from_millis(300), true).len(), 1);
+    }
+
+    #[test]
+    fn dispatch_reports_the_strongest_command() {
+        record_dispatch(&[
+            ShockerCommand { id: "1".to_string(), method: 2, intensity: 0.4, duration: 50 },
+            ShockerCommand { id: "2".to_string(), method: METHOD_SHOCK, intensity: 0.7, duration: 50 },
+        ]);
+        {
+            let dispatched = DISPATCHED.lock().unwrap();
+            assert!(dispatched.shock_active);
+            assert_eq!((dispatched.last_intensity, dispatched.last_method), (0.7, METHOD_SHOCK));
+        }
+
+        // Nothing sent, the last intensity stays for the avatar to show
+        record_dispatch(&[]);
+        let dispatched = DISPATCHED.lock().unwrap();
+        assert!(!dispatched.shock_active);
+        assert_eq!(dispatched.last_intensity, 0.7);
+    }
+}
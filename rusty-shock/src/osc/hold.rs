@@ -0,0 +1,50 @@
+use crate::config;
+use tokio::time::{Duration, Instant};
+
+// Safety net for contacts that are re-sent every tick while held (ramps, grabbed PhysBones)
+// A lost release or a contact left touching would otherwise fire forever, see max_hold_ms and stale_hold_ms in [safety]
+#[derive(Debug)]
+pub struct HoldTimer {
+    since: Instant,
+    last_seen: Instant,
+    timed_out: bool,
+}
+
+impl HoldTimer {
+    pub fn new(now: Instant) -> HoldTimer {
+        HoldTimer { since: now, last_seen: now, timed_out: false }
+    }
+
+    // A fresh value came in from VRChat, re-sends from the tick don't count
+    pub fn seen(&mut self, now: Instant) {
+        self.last_seen = now;
+    }
+
+    // Hold again from the start, EX: a ramp that starts over
+    pub fn restart(&mut self, now: Instant) {
+        *self = HoldTimer::new(now);
+    }
+
+    // Once timed out it stays that way, the contact has to be released first
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    // True while the hold may keep firing, logs once when it runs out
+    pub fn check(&mut self, address: &str, now: Instant) -> bool {
+        if self.timed_out {
+            return false;
+        }
+        let safety_config = &config::get_config().safety;
+        let reason = if safety_config.max_hold_ms > 0 && now.saturating_duration_since(self.since) >= Duration::from_millis(safety_config.max_hold_ms) {
+            format!("held longer than {} ms", safety_config.max_hold_ms)
+        } else if safety_config.stale_hold_ms > 0 && now.saturating_duration_since(self.last_seen) >= Duration::from_millis(safety_config.stale_hold_ms) {
+            format!("no new value for {} ms", safety_config.stale_hold_ms)
+        } else {
+            return true;
+        };
+        log::warn!("Letting go of {}, {}. It fires again once released and touched again", address, reason);
+        self.timed_out = true;
+        false
+    }
+}
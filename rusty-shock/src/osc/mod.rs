@@ -1,2 +1,15 @@
+pub mod cooldown;
+pub mod curves;
+pub mod feedback;
+pub mod hold;
 pub mod osc;
-pub mod touchpoints;
\ No newline at end of file
+pub mod oscquery;
+pub mod parameters;
+pub mod pattern;
+pub mod physbone;
+pub mod ramp;
+pub mod relay;
+pub mod source;
+pub mod tcp;
+pub mod touchpoints;
+pub mod velocity;
\ No newline at end of file
@@ -1,9 +1,28 @@
-use rosc::{OscMessage, OscPacket};
+use rosc::{OscBundle,OscMessage,OscPacket,OscTime};
 use std::net::{SocketAddr,IpAddr};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc::Sender;
+use tokio::time::{self,Duration};
 use crate::config;
+use crate::osc::oscquery;
+use crate::osc::parameters::ParameterStore;
+use crate::osc::relay;
+use crate::osc::source::SourceFilter;
+use crate::safety::estop;
+
+// Timetag meaning "now" in the OSC spec
+const IMMEDIATELY: OscTime = OscTime { seconds: 0, fractional: 1 };
+
+// Anything dated further ahead than this is most likely a sender with a wrong clock
+const MAX_SCHEDULE_AHEAD: Duration = Duration::from_secs(60);
+
+// Each scheduled bundle is a task sleeping until it is due, a sender flooding future bundles can't pile up more than this
+const MAX_PENDING_BUNDLES: usize = 256;
+
+static PENDING_BUNDLES: AtomicUsize = AtomicUsize::new(0);
 
 /*
 async fn send_to_osc(addr: &SocketAddr) -> async_osc::Result<()> {
@@ -19,30 +38,65 @@ async fn send_to_osc(addr: &SocketAddr) -> async_osc::Result<()> {
 }
 */
 
-pub async fn start_osc_server(sender: Sender<OscMessage>) -> Result<(), Box<dyn std::error::Error>> {
+// sources is shared with the TCP server so both go by the same allowlist and drop count
+pub async fn start_osc_server(store: Arc<ParameterStore>, sources: Arc<Mutex<SourceFilter>>) -> Result<(), Box<dyn std::error::Error>> {
     let osc_config = &config::get_config().osc;
     
     let ip_address = IpAddr::from_str(&osc_config.ip_address)?;
+    // Listen somewhere other than where VRChat is when bind_address is set
+    let bind_address = if osc_config.bind_address.is_empty() { ip_address } else { IpAddr::from_str(&osc_config.bind_address)? };
     
-    let listen_addr = SocketAddr::from((ip_address, osc_config.listen_port));
-    let send_addr = SocketAddr::from((ip_address, osc_config.send_port));
+    let listen_addr = SocketAddr::from((bind_address, osc_config.listen_port));
+    let send_addr = send_addr()?;
 
     //https://docs.rs/async-throttle/0.3.2/async_throttle/struct.RateLimiter.html
     
     log::debug!("\nOSC Config\n Listen Port: {}\n Send Port: {}\n IP Address: {}\n Listening on {}\n Sending on {}", osc_config.listen_port,osc_config.send_port,osc_config.ip_address,listen_addr, send_addr);
 
     let socket = UdpSocket::bind(listen_addr).await?;
+    run_osc_server(socket, store, sources).await
+}
+
+// Serve OSC on an already bound socket until it fails
+pub async fn run_osc_server(socket: UdpSocket, store: Arc<ParameterStore>, sources: Arc<Mutex<SourceFilter>>) -> Result<(), Box<dyn std::error::Error>> {
+    let local_addr = socket.local_addr()?;
+
+    // Lives as long as the server, dropping it withdraws the advertisement
+    let _oscquery = if config::get_config().oscquery.enabled {
+        match oscquery::OscQuery::start(local_addr, Arc::clone(&store)).await {
+            Ok(oscquery) => Some(oscquery),
+            Err(e) => {
+                log::error!("Failed to start OSCQuery, VRChat has to be pointed at port {} by hand: {}", local_addr.port(), e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    // A relay that is configured but can't start is a config mistake, don't run without it
+    let mut relay = relay::Relay::start(local_addr).await
+        .map_err(|e| format!("Failed to start the OSC relay: {}", e))?;
     let mut buf = [0u8; rosc::decoder::MTU];
 
     loop {
         let (size, addr) = socket.recv_from(&mut buf).await?;
+        let mut sources = sources.lock().expect("Source filter lock poisoned");
+        // Checked before decoding, spoofed packets never reach the relay or the touchpoints
+        if !sources.allows(addr.ip()) {
+            continue;
+        }
         match rosc::decoder::decode_udp(&buf[..size]) {
             Ok((_, packet)) => {
                 log::debug!("Received packet with size {} from: {}", size, addr);
-                if let Err(e) = handle_packet(packet, sender.clone()).await {
-                    log::error!("Failed to handle OSC packet: {}", e);
-                    // Decide what to do here - stop the server or just log the error
+                let packet = match sources.unlock(addr.ip(), packet) {
+                    Some(packet) => packet,
+                    None => continue,
+                };
+                if let Some(relay) = relay.as_mut() {
+                    let received = (!sources.needs_secret(addr.ip())).then(|| &buf[..size]);
+                    relay.forward(received, &packet);
                 }
+                handle_packet(packet, &store);
             }
             Err(e) => {
                 log::error!("Failed to decode OSC packet: {}", e);
@@ -54,17 +108,197 @@ pub async fn start_osc_server(sender: Sender<OscMessage>) -> Result<(), Box<dyn
     Ok(())
 }
 
-async fn handle_packet(packet: OscPacket, sender: Sender<OscMessage>) -> Result<(), String> {
+// Where the OSC client (EX: VRChat) listens, used for the feedback parameters
+// VRChat found over OSCQuery wins over ip_address:send_port
+pub fn send_addr() -> Result<SocketAddr, std::net::AddrParseError> {
+    if let Some(discovered) = oscquery::discovered_send_addr() {
+        return Ok(discovered);
+    }
+    let osc_config = &config::get_config().osc;
+    let ip_address = IpAddr::from_str(&osc_config.ip_address)?;
+    Ok(SocketAddr::from((ip_address, osc_config.send_port)))
+}
+
+pub fn handle_packet(packet: OscPacket, store: &Arc<ParameterStore>) {
     match packet {
-        OscPacket::Message(msg) => {
-            // Instead of breaking, return an error if the message can't be sent
-            sender.send(msg).await.map_err(|e| e.to_string())
+        OscPacket::Message(msg) => handle_message(msg, store),
+        OscPacket::Bundle(bundle) => handle_bundle(bundle, store),
+    }
+}
+
+fn handle_message(msg: OscMessage, store: &ParameterStore) {
+    // Emergency stop skips the parameter store so it is never coalesced or delayed
+    if estop::handle_osc(&msg) {
+        return;
+    }
+    // Overwrites any value the router hasn't picked up yet, the socket never waits on the router
+    store.update(msg);
+}
+
+// Bundles can hold messages and more bundles, each nested bundle is checked against its own timetag when it comes up
+// Messages go into the parameter store like any other, so several values for one address in a bundle
+// collapse into the last one, the router only ever sees the newest value per address
+fn handle_bundle(bundle: OscBundle, store: &Arc<ParameterStore>) {
+    log::debug!("OSC Bundle with {} element(s), timetag {:?}", bundle.content.len(), bundle.timetag);
+
+    let delay = match bundle_delay(bundle.timetag) {
+        Some(delay) => delay,
+        None => {
+            for packet in bundle.content {
+                handle_packet(packet, store);
+            }
+            return;
         }
-        OscPacket::Bundle(bundle) => {
-            // If you want to handle bundles, you would do it here.
-            // For now, we can just ignore them or log a message
-            log::debug!("OSC Bundle: {:?}", bundle);
-            Ok(())
-        },
+    };
+
+    if delay > MAX_SCHEDULE_AHEAD {
+        log::warn!("Dropping OSC bundle dated {:?} ahead, check the sender's clock", delay);
+        return;
+    }
+
+    let pending = match PendingBundle::reserve() {
+        Some(pending) => pending,
+        None => {
+            log::warn!("Dropping OSC bundle, already {} scheduled", MAX_PENDING_BUNDLES);
+            return;
+        }
+    };
+    log::debug!("Scheduling OSC bundle in {:?}", delay);
+    let store = Arc::clone(store);
+    let epoch = estop::epoch();
+    tokio::spawn(async move {
+        deliver_later(bundle, delay, store, epoch).await;
+        drop(pending);
+    });
+}
+
+// One of the MAX_PENDING_BUNDLES slots, given back when dropped
+struct PendingBundle;
+
+impl PendingBundle {
+    fn reserve() -> Option<PendingBundle> {
+        PENDING_BUNDLES.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pending| (pending < MAX_PENDING_BUNDLES).then_some(pending + 1))
+            .ok()
+            .map(|_| PendingBundle)
+    }
+}
+
+impl Drop for PendingBundle {
+    fn drop(&mut self) {
+        PENDING_BUNDLES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// An emergency stop while the bundle waits cancels it, re-arming before it is due doesn't bring it back
+async fn deliver_later(bundle: OscBundle, delay: Duration, store: Arc<ParameterStore>, epoch: u64) {
+    time::sleep(delay).await;
+    if estop::epoch() != epoch || estop::is_engaged() {
+        log::info!("Dropping OSC bundle scheduled before the emergency stop");
+        return;
+    }
+    for packet in bundle.content {
+        handle_packet(packet, &store);
+    }
+}
+
+// How long until the bundle is due, None if it is due now or already late
+fn bundle_delay(timetag: OscTime) -> Option<Duration> {
+    if timetag == IMMEDIATELY {
+        return None;
+    }
+    SystemTime::from(timetag).duration_since(SystemTime::now()).ok()
+        .filter(|delay| !delay.is_zero())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osc::touchpoints::{self, Device, TouchpointSet};
+    use rosc::OscType;
+    use std::collections::HashMap;
+
+    // A float contact sent over UDP ends up as an active command for every shocker and method of the touchpoint
+    // The touchpoints are built here, so nothing depends on the touchpoints.toml next to the test binary
+    #[tokio::test]
+    async fn udp_packet_reaches_the_command_map() {
+        let devices: Vec<Device> = vec![toml::from_str(r#"
+            address = "/avatar/parameters/udp_test"
+            method = [1, 2]
+            intensity = 1.0
+            duration = 500
+            ids = ["udp-test-a", "udp-test-b"]
+        "#).unwrap()];
+        let shocker_cooldowns = Box::leak(Box::new(HashMap::new()));
+        let set = TouchpointSet::new(Box::leak(devices.into_boxed_slice()), shocker_cooldowns, "/avatar/parameters");
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let store = Arc::new(ParameterStore::new());
+        let server_store = Arc::clone(&store);
+        tokio::spawn(async move {
+            let sources = Arc::new(Mutex::new(SourceFilter::new().unwrap()));
+            let _ = run_osc_server(socket, server_store, sources).await;
+        });
+
+        let packet = OscPacket::Message(OscMessage { addr: "/avatar/parameters/udp_test".to_string(), args: vec![OscType::Float(0.5)] });
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender.send_to(&rosc::encoder::encode(&packet).unwrap(), server_addr).await.unwrap();
+
+        time::timeout(Duration::from_secs(2), store.changed()).await.expect("packet never reached the parameter store");
+        let command_states = Arc::new(tokio::sync::Mutex::new(HashMap::new()));
+        for message in store.take() {
+            touchpoints::process_message(&message, &set, Arc::clone(&command_states)).await;
+        }
+
+        let command_states = command_states.lock().await;
+        let now = time::Instant::now();
+        for key in ["udp-test-a_1", "udp-test-a_2", "udp-test-b_1", "udp-test-b_2"] {
+            let state = command_states.get(key).unwrap_or_else(|| panic!("{} not in the command map", key));
+            assert!((state.intensity - 0.5).abs() < 1e-6);
+            assert_eq!(state.duration, 50);
+            assert!(state.expiry > now);
+        }
+        assert_eq!(command_states.len(), 4);
+    }
+
+    fn bundle() -> OscBundle {
+        OscBundle {
+            timetag: IMMEDIATELY,
+            content: vec![OscPacket::Message(OscMessage { addr: "/avatar/parameters/tail".to_string(), args: vec![OscType::Float(0.5)] })],
+        }
+    }
+
+    // Engaging for real would stop every other test, so the bundle is scheduled with the epoch from before a stop
+    #[tokio::test]
+    async fn bundle_scheduled_before_an_emergency_stop_is_dropped() {
+        let store = Arc::new(ParameterStore::new());
+        deliver_later(bundle(), Duration::from_millis(10), Arc::clone(&store), estop::epoch().wrapping_sub(1)).await;
+        assert!(store.take().is_empty());
+
+        deliver_later(bundle(), Duration::from_millis(10), Arc::clone(&store), estop::epoch()).await;
+        assert_eq!(store.take().len(), 1);
+    }
+
+    // Nothing else in the tests schedules bundles through handle_bundle, so every slot is free here
+    #[test]
+    fn pending_bundles_are_capped() {
+        let reserved: Vec<PendingBundle> = (0..MAX_PENDING_BUNDLES).map_while(|_| PendingBundle::reserve()).collect();
+        assert_eq!(reserved.len(), MAX_PENDING_BUNDLES);
+        assert!(PendingBundle::reserve().is_none());
+
+        drop(reserved);
+        assert!(PendingBundle::reserve().is_some());
+    }
+
+    // The router only sees the newest value per address, the earlier ones in the bundle are gone
+    #[test]
+    fn same_address_in_one_bundle_keeps_the_last_value() {
+        let store = Arc::new(ParameterStore::new());
+        let message = |value| OscPacket::Message(OscMessage { addr: "/avatar/parameters/tail".to_string(), args: vec![OscType::Float(value)] });
+        handle_packet(OscPacket::Bundle(OscBundle { timetag: IMMEDIATELY, content: vec![message(0.2), message(0.9)] }), &store);
+
+        let messages = store.take();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].args, vec![OscType::Float(0.9)]);
     }
 }
@@ -0,0 +1,399 @@
+// OSCQuery: advertise our OSC port over mDNS with a small HTTP JSON server describing it,
+// and find VRChat's own OSCQuery service so we know where to send without osc.send_port
+// https://github.com/Vidvox/OSCQueryProposal
+use crate::config;
+use crate::osc::parameters::ParameterStore;
+use crate::osc::{pattern, physbone, touchpoints};
+use crate::osc::touchpoints::{Device, ParameterType};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use once_cell::sync::Lazy;
+use rosc::{OscMessage, OscType};
+use serde_json::{json, Map, Value};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+const OSCJSON_SERVICE: &str = "_oscjson._tcp.local.";
+const OSC_SERVICE: &str = "_osc._udp.local.";
+// VRChat names its services VRChat-Client-XXXXXX
+const VRCHAT_SERVICE_PREFIX: &str = "VRChat-Client-";
+
+// Only the request line matters
+const MAX_REQUEST_BYTES: usize = 8192;
+
+// VRChat's OSC address as found over OSCQuery
+static DISCOVERED: Lazy<Mutex<Option<SocketAddr>>> = Lazy::new(|| Mutex::new(None));
+
+pub fn discovered_send_addr() -> Option<SocketAddr> {
+    *DISCOVERED.lock().expect("OSCQuery lock poisoned")
+}
+
+// Keeps the advertisement up, dropping it withdraws the services and stops the HTTP server
+pub struct OscQuery {
+    daemon: ServiceDaemon,
+    fullnames: Vec<String>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl OscQuery {
+    // osc_addr is the address the OSC server actually bound, so a listen_port of 0 is advertised as the port the OS picked
+    // The avatar VRChat has on is put into store once it is found
+    pub async fn start(osc_addr: SocketAddr, store: Arc<ParameterStore>) -> Result<OscQuery, Box<dyn std::error::Error + Send + Sync>> {
+        let oscquery_config = &config::get_config().oscquery;
+
+        // Serve the JSON on the same interface the OSC server listens on
+        // mDNS never advertises loopback, so listening on 127.0.0.1 the HTTP server has to be reachable where we are advertised
+        let http_ip = match osc_addr.ip() {
+            IpAddr::V4(ip) if ip.is_loopback() => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(ip) if ip.is_loopback() => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            ip => ip,
+        };
+        let listener = TcpListener::bind((http_ip, 0)).await?;
+        let http_port = listener.local_addr()?.port();
+        let responses = Arc::new(Responses {
+            name: oscquery_config.name.clone(),
+            osc_addr,
+            tree: parameter_tree(),
+        });
+        log::info!("OSCQuery HTTP server listening on {}:{}", http_ip, http_port);
+
+        let mut tasks = vec![tokio::spawn(serve(listener, responses))];
+
+        let daemon = ServiceDaemon::new()?;
+        let mut fullnames = Vec::new();
+        for (service_type, port) in [(OSCJSON_SERVICE, http_port), (OSC_SERVICE, osc_addr.port())] {
+            let service = service_info(service_type, &oscquery_config.name, osc_addr.ip(), port)?;
+            fullnames.push(service.get_fullname().to_string());
+            daemon.register(service)?;
+        }
+        log::info!("Advertising {} over OSCQuery, OSC on port {}", oscquery_config.name, osc_addr.port());
+
+        if oscquery_config.discover_vrchat {
+            let events = daemon.browse(OSCJSON_SERVICE)?;
+            tasks.push(tokio::spawn(discover(events, store)));
+        }
+
+        Ok(OscQuery { daemon, fullnames, tasks })
+    }
+}
+
+impl Drop for OscQuery {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        for fullname in &self.fullnames {
+            if let Err(e) = self.daemon.unregister(fullname) {
+                log::debug!("Failed to unregister {}: {}", fullname, e);
+            }
+        }
+        if let Err(e) = self.daemon.shutdown() {
+            log::debug!("Failed to stop the mDNS daemon: {}", e);
+        }
+    }
+}
+
+fn service_info(service_type: &str, name: &str, ip: IpAddr, port: u16) -> Result<ServiceInfo, mdns_sd::Error> {
+    let host_name = format!("{}.local.", name.to_ascii_lowercase());
+    if ip.is_unspecified() || ip.is_loopback() {
+        // Listening everywhere, advertise every interface address
+        // mdns-sd skips loopback interfaces, so a 127.0.0.1 address would never be announced, the host's own addresses reach it too
+        Ok(ServiceInfo::new(service_type, name, &host_name, "", port, None)?.enable_addr_auto())
+    } else {
+        ServiceInfo::new(service_type, name, &host_name, ip, port, None)
+    }
+}
+
+struct Responses {
+    name: String,
+    osc_addr: SocketAddr,
+    tree: Value,
+}
+
+// local_ip is the address the query came in on, the client can reach OSC there too
+fn host_info(name: &str, osc_addr: SocketAddr, local_ip: IpAddr) -> Value {
+    // 0.0.0.0 isn't somewhere a client can send to
+    let osc_ip = if osc_addr.ip().is_unspecified() { local_ip } else { osc_addr.ip() };
+    json!({
+        "NAME": name,
+        "OSC_IP": osc_ip.to_string(),
+        "OSC_PORT": osc_addr.port(),
+        "OSC_TRANSPORT": "UDP",
+        "EXTENSIONS": {
+            "ACCESS": true,
+            "TYPE": true,
+            "FULL_PATH": true,
+            "CONTENTS": true,
+        },
+    })
+}
+
+// Every address we handle, for OSCQuery clients to see what we take
+// It doesn't limit what a client sends, anything not listed is still received and ignored
+fn parameter_tree() -> Value {
+    let osc_config = &config::get_config().osc;
+    let estop_config = &config::get_config().emergency_stop;
+
+    let mut addresses: Vec<(String, &str)> = vec![("/avatar/change".to_string(), "s")];
+    // Every avatar's touchpoints, the tree is built once and VRChat only reads it when it connects
+    for device in touchpoints::get_config().all_devices() {
+        let address = pattern::full_address(&osc_config.touchpoint_prefix, &device.address);
+        // Wildcard touchpoints can't be listed, they still fire for whatever matching parameters arrive
+        if address.contains(['*', '?', '[', '{']) {
+            continue;
+        }
+        let osc_type = touchpoint_type(device, &address);
+        addresses.push((address, osc_type));
+    }
+    for address in [&estop_config.osc_address, &estop_config.rearm_osc_address] {
+        if !address.is_empty() {
+            addresses.push((address.clone(), "T"));
+        }
+    }
+
+    let mut root = container("/");
+    for (address, osc_type) in addresses {
+        insert(&mut root, &address, osc_type);
+    }
+    root
+}
+
+// OSC type tag of a touchpoint's parameter, PhysBone suffixes always have the type VRChat gives them
+fn touchpoint_type(device: &Device, address: &str) -> &'static str {
+    if let Some(suffix) = physbone::Suffix::from_address(address) {
+        return if suffix.is_state() { "T" } else { "f" };
+    }
+    let parameter_type = device.parameter_type.unwrap_or(if device.int_values.is_empty() { ParameterType::Float } else { ParameterType::Int });
+    match parameter_type {
+        ParameterType::Float => "f",
+        ParameterType::Bool => "T",
+        ParameterType::Int => "i",
+    }
+}
+
+fn container(full_path: &str) -> Value {
+    json!({ "FULL_PATH": full_path, "ACCESS": 0, "CONTENTS": {} })
+}
+
+fn insert(root: &mut Value, address: &str, osc_type: &str) {
+    let mut node = root;
+    let mut full_path = String::new();
+    let parts: Vec<&str> = address.split('/').filter(|part| !part.is_empty()).collect();
+
+    for (index, part) in parts.iter().enumerate() {
+        full_path.push('/');
+        full_path.push_str(part);
+        let contents = match node.get_mut("CONTENTS").and_then(Value::as_object_mut) {
+            Some(contents) => contents,
+            // Already listed as a parameter, it can't have children too
+            None => return,
+        };
+        let is_leaf = index == parts.len() - 1;
+        node = contents.entry(part.to_string()).or_insert_with(|| {
+            if is_leaf {
+                // 2 is write only, we receive these and never report a value
+                json!({ "FULL_PATH": full_path, "ACCESS": 2, "TYPE": osc_type })
+            } else {
+                container(&full_path)
+            }
+        });
+    }
+}
+
+// The node at an address in the tree, None if there isn't one
+fn find<'a>(tree: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('/')
+        .filter(|part| !part.is_empty())
+        .try_fold(tree, |node, part| node.get("CONTENTS")?.get(part))
+}
+
+async fn serve(listener: TcpListener, responses: Arc<Responses>) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::error!("OSCQuery HTTP server failed: {}", e);
+                return;
+            }
+        };
+        let responses = Arc::clone(&responses);
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(stream, &responses).await {
+                log::debug!("OSCQuery request from {} failed: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_request(mut stream: TcpStream, responses: &Responses) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    log::trace!("OSCQuery request: {} {}", method, target);
+
+    let (status, body) = match (method, query) {
+        ("GET", "HOST_INFO") => {
+            let local_ip = stream.local_addr()?.ip();
+            ("200 OK", host_info(&responses.name, responses.osc_addr, local_ip).to_string())
+        },
+        ("GET", _) => match find(&responses.tree, path) {
+            Some(node) => ("200 OK", node.to_string()),
+            None => ("404 Not Found", String::new()),
+        },
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// Follow VRChat's OSCQuery service and remember where it wants OSC sent
+async fn discover(events: mdns_sd::Receiver<ServiceEvent>, store: Arc<ParameterStore>) {
+    while let Ok(event) = events.recv_async().await {
+        match event {
+            ServiceEvent::ServiceResolved(info) if info.get_fullname().starts_with(VRCHAT_SERVICE_PREFIX) => {
+                let ip = match info.get_addresses().iter().find(|ip| ip.is_ipv4()).or(info.get_addresses().iter().next()) {
+                    Some(ip) => *ip,
+                    None => continue,
+                };
+                match vrchat_osc_addr(ip, info.get_port()).await {
+                    Ok(send_addr) => {
+                        log::info!("Found VRChat over OSCQuery ({}), sending OSC to {}", info.get_fullname(), send_addr);
+                        *DISCOVERED.lock().expect("OSCQuery lock poisoned") = Some(send_addr);
+                    },
+                    Err(e) => log::warn!("Found VRChat over OSCQuery but could not read its host info: {}", e),
+                }
+                // VRChat only sends /avatar/change when the avatar changes, so without this we'd use the default set until then
+                match current_avatar(ip, info.get_port()).await {
+                    Ok(Some(avatar_id)) => {
+                        log::info!("VRChat is wearing {}", avatar_id);
+                        store.update(OscMessage { addr: touchpoints::AVATAR_CHANGE.to_string(), args: vec![OscType::String(avatar_id)] });
+                    },
+                    Ok(None) => log::debug!("VRChat's OSCQuery has no avatar yet"),
+                    Err(e) => log::warn!("Could not read the current avatar from VRChat's OSCQuery: {}", e),
+                }
+            },
+            ServiceEvent::ServiceRemoved(_, fullname) if fullname.starts_with(VRCHAT_SERVICE_PREFIX) => {
+                log::info!("VRChat OSCQuery service {} is gone, back to osc.ip_address:osc.send_port", fullname);
+                *DISCOVERED.lock().expect("OSCQuery lock poisoned") = None;
+            },
+            _ => {},
+        }
+    }
+}
+
+// Ask VRChat's OSCQuery server where it receives OSC
+async fn vrchat_osc_addr(ip: IpAddr, http_port: u16) -> Result<SocketAddr, Box<dyn std::error::Error + Send + Sync>> {
+    let url = format!("http://{}/?HOST_INFO", SocketAddr::new(ip, http_port));
+    let host_info: Map<String, Value> = reqwest::get(&url).await?.json().await?;
+
+    let osc_port = host_info.get("OSC_PORT").and_then(Value::as_u64)
+        .and_then(|port| u16::try_from(port).ok())
+        .ok_or("HOST_INFO has no OSC_PORT")?;
+    // Fall back to the address the service was resolved at
+    let osc_ip = host_info.get("OSC_IP").and_then(Value::as_str)
+        .and_then(|osc_ip| osc_ip.parse::<IpAddr>().ok())
+        .filter(|osc_ip| !osc_ip.is_unspecified())
+        .unwrap_or(ip);
+
+    Ok(SocketAddr::new(osc_ip, osc_port))
+}
+
+// The avatar ID VRChat has on, its OSCQuery tree keeps the last /avatar/change value
+async fn current_avatar(ip: IpAddr, http_port: u16) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let url = format!("http://{}{}", SocketAddr::new(ip, http_port), touchpoints::AVATAR_CHANGE);
+    let node: Map<String, Value> = reqwest::get(&url).await?.json().await?;
+    Ok(node.get("VALUE")
+        .and_then(Value::as_array)
+        .and_then(|value| value.first())
+        .and_then(Value::as_str)
+        .filter(|avatar_id| !avatar_id.is_empty())
+        .map(str::to_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::test_server::TestServer;
+    use tokio::time::{self, Duration};
+
+    // Listening everywhere, HOST_INFO has to point at the interface the client asked on, not 127.0.0.1 or 0.0.0.0
+    #[tokio::test]
+    async fn host_info_reports_the_interface_asked_on() {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await.unwrap();
+        let http_port = listener.local_addr().unwrap().port();
+        let responses = Arc::new(Responses {
+            name: "ShockRS-Test".to_string(),
+            osc_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 9101),
+            tree: parameter_tree(),
+        });
+        tokio::spawn(serve(listener, responses));
+
+        let osc_addr = vrchat_osc_addr(IpAddr::V4(Ipv4Addr::LOCALHOST), http_port).await.unwrap();
+        assert_eq!(osc_addr, SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9101));
+    }
+
+    #[tokio::test]
+    async fn current_avatar_is_read_from_the_tree() {
+        let mut server = TestServer::start(200, r#"{"FULL_PATH":"/avatar/change","ACCESS":3,"TYPE":"s","VALUE":["avtr_test"]}"#).await;
+        let avatar_id = current_avatar(server.addr.ip(), server.addr.port()).await.unwrap();
+        assert_eq!(avatar_id.as_deref(), Some("avtr_test"));
+        assert_eq!(server.request().await.path, "/avatar/change");
+    }
+
+    // Bound to 127.0.0.1 we are still announced, on the host's own addresses, and what resolves leads back to our OSC port
+    #[tokio::test]
+    async fn loopback_bind_is_advertised() {
+        let osc_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9102);
+        let oscquery = OscQuery::start(osc_addr, Arc::new(ParameterStore::new())).await.unwrap();
+        let fullname = oscquery.fullnames[0].clone();
+
+        let browser = ServiceDaemon::new().unwrap();
+        let events = browser.browse(OSCJSON_SERVICE).unwrap();
+        let info = time::timeout(Duration::from_secs(10), async {
+            loop {
+                match events.recv_async().await.unwrap() {
+                    ServiceEvent::ServiceResolved(info) if info.get_fullname() == fullname => return info,
+                    _ => {},
+                }
+            }
+        }).await.expect("our OSCQuery service was never resolved");
+        let _ = browser.shutdown();
+
+        let ip = *info.get_addresses().iter().next().unwrap();
+        assert!(!ip.is_loopback());
+        assert_eq!(vrchat_osc_addr(ip, info.get_port()).await.unwrap(), osc_addr);
+    }
+
+    #[test]
+    fn touchpoint_types_are_advertised() {
+        let device = |extra: &str| -> Device {
+            toml::from_str(&format!("address = \"tail\"\nmethod = [2]\nintensity = 1.0\nduration = 300\nids = [\"3863\"]\n{}", extra)).unwrap()
+        };
+        assert_eq!(touchpoint_type(&device(""), "/avatar/parameters/tail"), "f");
+        assert_eq!(touchpoint_type(&device("parameter_type = \"bool\""), "/avatar/parameters/tail"), "T");
+        assert_eq!(touchpoint_type(&device("int_values = { \"1\" = 0.5 }"), "/avatar/parameters/tail"), "i");
+        assert_eq!(touchpoint_type(&device("parameter_type = \"int\""), "/avatar/parameters/tail"), "i");
+        // PhysBone parameters are whatever VRChat makes them
+        assert_eq!(touchpoint_type(&device("parameter_type = \"int\""), "/avatar/parameters/Tail_IsGrabbed"), "T");
+        assert_eq!(touchpoint_type(&device(""), "/avatar/parameters/Tail_Stretch"), "f");
+    }
+}
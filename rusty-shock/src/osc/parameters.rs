@@ -0,0 +1,128 @@
+use rosc::OscMessage;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::Notify;
+
+// Latest-value store between the OSC server and the touchpoint router
+// VRChat can send a parameter many times between router passes, we only care about the newest value per address
+// so a burst for one address collapses into a single slot instead of queueing up (or getting dropped by a full channel)
+pub struct ParameterStore {
+    slots: Mutex<HashMap<String, OscMessage>>,
+    changed: Notify,
+    received: AtomicU64,
+    coalesced: AtomicU64,
+}
+
+impl ParameterStore {
+    pub fn new() -> ParameterStore {
+        ParameterStore {
+            slots: Mutex::new(HashMap::new()),
+            changed: Notify::new(),
+            received: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+        }
+    }
+
+    // Store the newest value for the message address and wake the router
+    // Never blocks on the router, so the UDP socket keeps draining under load
+    pub fn update(&self, message: OscMessage) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+        let replaced = {
+            let mut slots = self.slots.lock().expect("Parameter store lock poisoned");
+            slots.insert(message.addr.clone(), message).is_some()
+        };
+        if replaced {
+            // The router never saw the previous value for this address
+            self.coalesced.fetch_add(1, Ordering::Relaxed);
+        }
+        // notify_one keeps a permit if the router isn't waiting yet, so a wakeup can't be lost
+        self.changed.notify_one();
+    }
+
+    // Wait until at least one slot has been updated since the last take
+    pub async fn changed(&self) {
+        self.changed.notified().await;
+    }
+
+    // Take every pending value, leaving the slots empty
+    pub fn take(&self) -> Vec<OscMessage> {
+        let mut slots = self.slots.lock().expect("Parameter store lock poisoned");
+        slots.drain().map(|(_, message)| message).collect()
+    }
+
+    // Total messages handed to the store
+    pub fn received_count(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    // Messages that were overwritten by a newer value before the router picked them up
+    pub fn coalesced_count(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rosc::OscType;
+    use std::sync::Arc;
+    use tokio::time::{self, Duration};
+
+    fn message(addr: &str, value: f32) -> OscMessage {
+        OscMessage { addr: addr.to_string(), args: vec![OscType::Float(value)] }
+    }
+
+    #[test]
+    fn latest_value_per_address_wins() {
+        let store = ParameterStore::new();
+        store.update(message("/avatar/parameters/tail", 0.1));
+        store.update(message("/avatar/parameters/tail", 0.7));
+        store.update(message("/avatar/parameters/ears", 0.3));
+
+        let mut messages = store.take();
+        messages.sort_by(|a, b| a.addr.cmp(&b.addr));
+        assert_eq!(messages, vec![message("/avatar/parameters/ears", 0.3), message("/avatar/parameters/tail", 0.7)]);
+        assert_eq!(store.received_count(), 3);
+        assert_eq!(store.coalesced_count(), 1);
+    }
+
+    #[test]
+    fn take_drains_the_store() {
+        let store = ParameterStore::new();
+        store.update(message("/avatar/parameters/tail", 0.5));
+        assert_eq!(store.take().len(), 1);
+        assert!(store.take().is_empty());
+
+        // Taken values don't count as coalesced when the address comes up again
+        store.update(message("/avatar/parameters/tail", 0.6));
+        assert_eq!(store.coalesced_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn update_wakes_a_waiting_router() {
+        let store = Arc::new(ParameterStore::new());
+        let router = tokio::spawn({
+            let store = Arc::clone(&store);
+            async move {
+                store.changed().await;
+                store.take()
+            }
+        });
+        // Let the router start waiting first
+        time::sleep(Duration::from_millis(20)).await;
+        store.update(message("/avatar/parameters/tail", 0.5));
+
+        let messages = time::timeout(Duration::from_secs(2), router).await.expect("router never woke up").unwrap();
+        assert_eq!(messages, vec![message("/avatar/parameters/tail", 0.5)]);
+    }
+
+    // An update before the router waits leaves a permit, so the wakeup isn't lost
+    #[tokio::test]
+    async fn update_before_waiting_is_not_lost() {
+        let store = ParameterStore::new();
+        store.update(message("/avatar/parameters/tail", 0.5));
+        time::timeout(Duration::from_secs(2), store.changed()).await.expect("wakeup was lost");
+        assert_eq!(store.take().len(), 1);
+    }
+}
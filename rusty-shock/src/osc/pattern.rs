@@ -0,0 +1,148 @@
+use regex::Regex;
+
+// OSC 1.0 address patterns, compiled to a regex once when touchpoints load
+//   *         any run of characters inside one address part
+//   ?         any single character inside one address part
+//   [abc]     one of the listed characters, [a-z] ranges and [!abc] negation work too
+//   {foo,bar} one of the listed strings
+pub fn compile(pattern: &str) -> Result<Regex, String> {
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                let (class, closed) = take_until(&mut chars, ']');
+                if !closed || class.is_empty() {
+                    return Err(format!("empty or unterminated [ in {}", pattern));
+                }
+                regex.push('[');
+                let class = match class.strip_prefix('!') {
+                    Some(negated) => {
+                        regex.push_str("^/");
+                        negated.to_string()
+                    },
+                    None => class,
+                };
+                for c in class.chars() {
+                    match c {
+                        // Ranges stay ranges
+                        '-' => regex.push('-'),
+                        c => regex.push_str(&regex::escape(&c.to_string())),
+                    }
+                }
+                regex.push(']');
+            },
+            '{' => {
+                let (alternatives, closed) = take_until(&mut chars, '}');
+                if !closed {
+                    return Err(format!("unterminated {{ in {}", pattern));
+                }
+                let alternatives: Vec<String> = alternatives.split(',').map(regex::escape).collect();
+                regex.push_str(&format!("(?:{})", alternatives.join("|")));
+            },
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+
+    Regex::new(&regex).map_err(|e| e.to_string())
+}
+
+// Everything up to the closing character, and whether it was found
+fn take_until(chars: &mut std::str::Chars, end: char) -> (String, bool) {
+    let mut taken = String::new();
+    for c in chars.by_ref() {
+        if c == end {
+            return (taken, true);
+        }
+        taken.push(c);
+    }
+    (taken, false)
+}
+
+// Touchpoint addresses without a leading / are relative to the prefix, EX: "tail" -> "/avatar/parameters/tail"
+pub fn full_address(prefix: &str, address: &str) -> String {
+    if address.starts_with('/') {
+        address.to_string()
+    } else {
+        format!("{}/{}", prefix.trim_end_matches('/'), address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, address: &str) -> bool {
+        compile(pattern).unwrap().is_match(address)
+    }
+
+    #[test]
+    fn question_mark_is_one_character_in_one_part() {
+        assert!(matches("/avatar/parameters/Hand?", "/avatar/parameters/HandL"));
+        assert!(!matches("/avatar/parameters/Hand?", "/avatar/parameters/Hand"));
+        assert!(!matches("/avatar/parameters/Hand?", "/avatar/parameters/HandLeft"));
+        assert!(!matches("/avatar/parameters?tail", "/avatar/parameters/tail"));
+    }
+
+    #[test]
+    fn star_stays_inside_one_part() {
+        assert!(matches("/avatar/parameters/Hand*", "/avatar/parameters/Hand"));
+        assert!(matches("/avatar/parameters/Hand*", "/avatar/parameters/HandLeft"));
+        assert!(!matches("/avatar/parameters/Hand*", "/avatar/parameters/HandLeft/Touch"));
+        assert!(matches("/avatar/*/tail", "/avatar/parameters/tail"));
+    }
+
+    #[test]
+    fn character_classes_and_ranges() {
+        assert!(matches("/avatar/parameters/Hand[LR]", "/avatar/parameters/HandL"));
+        assert!(!matches("/avatar/parameters/Hand[LR]", "/avatar/parameters/HandX"));
+        assert!(matches("/avatar/parameters/Finger[1-3]", "/avatar/parameters/Finger2"));
+        assert!(!matches("/avatar/parameters/Finger[1-3]", "/avatar/parameters/Finger4"));
+    }
+
+    #[test]
+    fn negated_class_never_crosses_a_part() {
+        assert!(matches("/avatar/parameters/Hand[!L]", "/avatar/parameters/HandR"));
+        assert!(!matches("/avatar/parameters/Hand[!L]", "/avatar/parameters/HandL"));
+        assert!(!matches("/avatar/parameters[!a]tail", "/avatar/parameters/tail"));
+    }
+
+    #[test]
+    fn alternatives_match_whole_strings() {
+        assert!(matches("/avatar/parameters/{tail,ears}", "/avatar/parameters/tail"));
+        assert!(matches("/avatar/parameters/{tail,ears}", "/avatar/parameters/ears"));
+        assert!(!matches("/avatar/parameters/{tail,ears}", "/avatar/parameters/tails"));
+        assert!(!matches("/avatar/parameters/{tail,ears}", "/avatar/parameters/tail,ears"));
+    }
+
+    // VRChat parameter names can hold characters that mean something in a regex
+    #[test]
+    fn regex_characters_are_literal() {
+        assert!(matches("/avatar/parameters/Tail.Base", "/avatar/parameters/Tail.Base"));
+        assert!(!matches("/avatar/parameters/Tail.Base", "/avatar/parameters/TailxBase"));
+        assert!(matches("/avatar/parameters/Tail+(1)$", "/avatar/parameters/Tail+(1)$"));
+        assert!(matches("/avatar/parameters/{a.b,c|d}", "/avatar/parameters/c|d"));
+        assert!(!matches("/avatar/parameters/{a.b,c|d}", "/avatar/parameters/c"));
+        assert!(matches("/avatar/parameters/[.]", "/avatar/parameters/."));
+        assert!(!matches("/avatar/parameters/[.]", "/avatar/parameters/x"));
+    }
+
+    #[test]
+    fn malformed_patterns_are_errors() {
+        assert!(compile("/avatar/parameters/Hand[LR").is_err());
+        assert!(compile("/avatar/parameters/Hand[]").is_err());
+        assert!(compile("/avatar/parameters/{tail,ears").is_err());
+        assert!(compile("/avatar/parameters/Finger[3-1]").is_err());
+    }
+
+    #[test]
+    fn relative_addresses_get_the_prefix() {
+        assert_eq!(full_address("/avatar/parameters/", "tail"), "/avatar/parameters/tail");
+        assert_eq!(full_address("/avatar/parameters", "tail"), "/avatar/parameters/tail");
+        assert_eq!(full_address("/avatar/parameters", "/tracking/tail"), "/tracking/tail");
+    }
+}
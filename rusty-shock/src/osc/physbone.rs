@@ -0,0 +1,133 @@
+use crate::osc::hold::HoldTimer;
+use once_cell::sync::Lazy;
+use rosc::OscType;
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+// VRChat PhysBone parameters, a bone with parameter "Tail" sends Tail_IsGrabbed, Tail_Angle and so on
+// A touchpoint binds to one of them by using the full name as its address, EX: address = "Tail_Stretch"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Suffix {
+    IsGrabbed,
+    IsPosed,
+    Angle,
+    Stretch,
+    Squish,
+}
+
+const SUFFIXES: [(&str, Suffix); 5] = [
+    ("_IsGrabbed", Suffix::IsGrabbed),
+    ("_IsPosed", Suffix::IsPosed),
+    ("_Angle", Suffix::Angle),
+    ("_Stretch", Suffix::Stretch),
+    ("_Squish", Suffix::Squish),
+];
+
+// Addresses whose IsGrabbed/IsPosed is currently true
+pub static HELD: Lazy<Mutex<Held>> = Lazy::new(|| Mutex::new(Held {
+    bones: HashMap::new(),
+}));
+
+pub struct Held {
+    bones: HashMap<String, HoldTimer>,
+}
+
+impl Held {
+    // A new IsGrabbed/IsPosed value, a bone that was let go for holding too long stays that way until it is released
+    pub fn set(&mut self, address: &str, held: bool, now: Instant) {
+        if !held {
+            self.bones.remove(address);
+            return;
+        }
+        self.bones.entry(address.to_string())
+            .or_insert_with(|| HoldTimer::new(now))
+            .seen(now);
+    }
+
+    // Any parameter of the bone counts as a fresh value for it, Angle and Stretch keep coming in while it is moved around
+    pub fn seen(&mut self, address: &str, now: Instant) {
+        let bone = bone(address);
+        for (_, hold) in self.bones.iter_mut().filter(|(held, _)| self::bone(held) == bone) {
+            hold.seen(now);
+        }
+    }
+
+    // Addresses to keep active this tick, ones held too long or gone quiet are left out
+    pub fn held(&mut self, now: Instant) -> Vec<String> {
+        self.bones.iter_mut()
+            .filter_map(|(address, hold)| hold.check(address, now).then(|| address.clone()))
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.bones.clear();
+    }
+
+    pub fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        self.bones.retain(|address, _| keep(address));
+    }
+}
+
+// The address without its PhysBone suffix, EX: "/avatar/parameters/Tail_IsGrabbed" -> "/avatar/parameters/Tail"
+fn bone(address: &str) -> &str {
+    SUFFIXES.iter()
+        .find_map(|(suffix, _)| address.strip_suffix(suffix))
+        .unwrap_or(address)
+}
+
+impl Suffix {
+    pub fn from_address(address: &str) -> Option<Suffix> {
+        SUFFIXES.iter()
+            .find(|(suffix, _)| address.ends_with(suffix))
+            .map(|(_, suffix)| *suffix)
+    }
+
+    // IsGrabbed and IsPosed are on/off states that stay true for as long as the bone is held
+    pub fn is_state(&self) -> bool {
+        matches!(self, Suffix::IsGrabbed | Suffix::IsPosed)
+    }
+
+    // The parameter as a 0.0 - 1.0 contact value, None if it isn't the type VRChat sends for this suffix
+    pub fn value(&self, arg: Option<&OscType>) -> Option<f32> {
+        match (self.is_state(), arg) {
+            (true, Some(OscType::Bool(state))) => Some(if *state { 1.0 } else { 0.0 }),
+            // Some OSC tools send bools as ints
+            (true, Some(OscType::Int(state))) => Some(if *state != 0 { 1.0 } else { 0.0 }),
+            // Angle is 0 - 180 degrees, Stretch and Squish 0 - 1, VRChat already normalizes all of them
+            (false, Some(OscType::Float(value))) => Some(value.clamp(0.0, 1.0)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use tokio::time::Duration;
+
+    #[test]
+    fn moving_the_bone_keeps_the_grab_alive() {
+        let stale = Duration::from_millis(config::get_config().safety.stale_hold_ms);
+        assert!(!stale.is_zero(), "test expects stale_hold_ms to be enabled in config.toml");
+        let mut held = Held { bones: HashMap::new() };
+        let now = Instant::now();
+
+        held.set("/avatar/parameters/Tail_IsGrabbed", true, now);
+        held.seen("/avatar/parameters/Tail_Angle", now + stale / 2);
+        // Another bone doesn't count
+        held.seen("/avatar/parameters/Ear_Angle", now + stale);
+        assert_eq!(held.held(now + stale), vec!["/avatar/parameters/Tail_IsGrabbed".to_string()]);
+
+        // Then it goes quiet, and stays let go even if another true comes in
+        assert!(held.held(now + stale / 2 + stale).is_empty());
+        held.set("/avatar/parameters/Tail_IsGrabbed", true, now + stale * 2);
+        assert!(held.held(now + stale * 2).is_empty());
+
+        // Released and grabbed again starts over
+        held.set("/avatar/parameters/Tail_IsGrabbed", false, now + stale * 2);
+        held.set("/avatar/parameters/Tail_IsGrabbed", true, now + stale * 2);
+        assert_eq!(held.held(now + stale * 2).len(), 1);
+    }
+}
@@ -0,0 +1,177 @@
+use crate::osc::hold::HoldTimer;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+// Hold to escalate, the longer a contact is held the closer the touchpoint gets to its full intensity
+// In touchpoints.toml: ramp = { start = 0.1, rate = 0.2, grace = 1000 }
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Ramp {
+    // Fraction of the touchpoint intensity when the contact starts
+    pub start: f32,
+    // Added to the fraction every second the contact is held
+    pub rate: f32,
+    // Milliseconds a contact can be let go before the ramp starts over
+    pub grace: u64,
+}
+
+impl Default for Ramp {
+    fn default() -> Self {
+        Ramp {
+            start: 0.1,
+            rate: 0.1,
+            grace: 1000,
+        }
+    }
+}
+
+struct Contact {
+    started: Instant,
+    // None while held
+    released: Option<Instant>,
+    grace: Duration,
+    hold: HoldTimer,
+}
+
+impl Contact {
+    fn expired(&self, now: Instant) -> bool {
+        self.released.is_some_and(|released| now.saturating_duration_since(released) > self.grace)
+    }
+}
+
+// Address VRChat sent -> the contact being ramped, a wildcard touchpoint ramps each matching contact on its own
+pub static CONTACTS: Lazy<Mutex<Contacts>> = Lazy::new(|| Mutex::new(Contacts {
+    contacts: HashMap::new(),
+}));
+
+pub struct Contacts {
+    contacts: HashMap<String, Contact>,
+}
+
+impl Contacts {
+    // Record a value from a ramped touchpoint and return the current ramp level, 0.0 once released
+    pub fn update(&mut self, address: &str, ramp: &Ramp, held: bool, now: Instant) -> f32 {
+        if !held {
+            if let Some(contact) = self.contacts.get_mut(address) {
+                if contact.hold.timed_out() {
+                    // Let go of for good, the next touch starts a fresh ramp
+                    self.contacts.remove(address);
+                } else {
+                    contact.released.get_or_insert(now);
+                }
+            }
+            return 0.0;
+        }
+
+        let grace = Duration::from_millis(ramp.grace);
+        let contact = self.contacts.entry(address.to_string()).or_insert_with(|| {
+            log::debug!("Ramp started for {}", address);
+            Contact { started: now, released: None, grace, hold: HoldTimer::new(now) }
+        });
+        if contact.expired(now) {
+            log::debug!("Ramp for {} restarted, released longer than {} ms", address, ramp.grace);
+            contact.started = now;
+        }
+        if contact.released.take().is_some() {
+            // Touched again, the ramp carries on within the grace period but the hold is a new one
+            contact.hold.restart(now);
+        }
+        contact.hold.seen(now);
+        if !contact.hold.check(address, now) {
+            return 0.0;
+        }
+
+        level(ramp, now.saturating_duration_since(contact.started))
+    }
+
+    // Current ramp level of a held contact for the re-send on every tick, doesn't count as a new value from VRChat
+    pub fn level(&self, address: &str, ramp: &Ramp, now: Instant) -> f32 {
+        match self.contacts.get(address) {
+            Some(contact) if contact.released.is_none() && !contact.hold.timed_out() => level(ramp, now.saturating_duration_since(contact.started)),
+            _ => 0.0,
+        }
+    }
+
+    // Addresses with a contact still held, these are re-sent every tick so the ramp keeps climbing without new OSC values
+    // Contacts released longer than their grace period are forgotten here, ones held too long or gone quiet are left out
+    pub fn held(&mut self, now: Instant) -> Vec<String> {
+        self.contacts.retain(|address, contact| {
+            let expired = contact.expired(now);
+            if expired {
+                log::debug!("Ramp for {} reset", address);
+            }
+            !expired
+        });
+        self.contacts.iter_mut()
+            .filter(|(_, contact)| contact.released.is_none())
+            .filter_map(|(address, contact)| contact.hold.check(address, now).then(|| address.clone()))
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.contacts.clear();
+    }
+
+    pub fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        self.contacts.retain(|address, _| keep(address));
+    }
+}
+
+fn level(ramp: &Ramp, held_for: Duration) -> f32 {
+    (ramp.start + ramp.rate * held_for.as_secs_f32()).clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    fn contacts() -> Contacts {
+        Contacts { contacts: HashMap::new() }
+    }
+
+    #[test]
+    fn quiet_contact_is_let_go_until_released() {
+        let stale = Duration::from_millis(config::get_config().safety.stale_hold_ms);
+        assert!(!stale.is_zero(), "test expects stale_hold_ms to be enabled in config.toml");
+        let ramp = Ramp::default();
+        let mut contacts = contacts();
+        let now = Instant::now();
+
+        assert!(contacts.update("/avatar/parameters/Ramp", &ramp, true, now) > 0.0);
+        assert_eq!(contacts.held(now + stale / 2), vec!["/avatar/parameters/Ramp".to_string()]);
+
+        // Re-sends from the tick don't keep it alive
+        assert!(contacts.level("/avatar/parameters/Ramp", &ramp, now + stale / 2) > 0.0);
+        assert!(contacts.held(now + stale).is_empty());
+        assert_eq!(contacts.level("/avatar/parameters/Ramp", &ramp, now + stale), 0.0);
+        // Still touching doesn't bring it back
+        assert_eq!(contacts.update("/avatar/parameters/Ramp", &ramp, true, now + stale), 0.0);
+
+        // Letting go and touching again does, from the start of the ramp
+        let later = now + stale * 2;
+        contacts.update("/avatar/parameters/Ramp", &ramp, false, later);
+        assert_eq!(contacts.update("/avatar/parameters/Ramp", &ramp, true, later), ramp.start);
+    }
+
+    #[test]
+    fn contact_held_too_long_is_let_go() {
+        let max_hold = Duration::from_millis(config::get_config().safety.max_hold_ms);
+        assert!(!max_hold.is_zero(), "test expects max_hold_ms to be enabled in config.toml");
+        let ramp = Ramp::default();
+        let mut contacts = contacts();
+        let now = Instant::now();
+
+        // Fresh values keep coming in, but it has been held for too long
+        let mut at = now;
+        while at < now + max_hold {
+            assert!(contacts.update("/avatar/parameters/Ramp", &ramp, true, at) > 0.0);
+            at += Duration::from_secs(1);
+        }
+        assert_eq!(contacts.update("/avatar/parameters/Ramp", &ramp, true, now + max_hold), 0.0);
+        assert!(contacts.held(now + max_hold).is_empty());
+    }
+}
@@ -0,0 +1,227 @@
+// Forward what the OSC server receives to other local OSC apps (EX: face tracking, chatbox)
+// VRChat only sends to one port, so without this nothing else could listen next to us
+// Only our own listen address is caught, two relays (EX: two ShockRS, or another OSC router) pointing at each other
+// will send every packet back and forth forever
+use crate::config;
+use crate::osc::pattern;
+use regex::Regex;
+use rosc::{OscBundle, OscPacket};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+struct Target {
+    addr: SocketAddr,
+    queue: mpsc::Sender<Arc<Vec<u8>>>,
+    // Only warn once per run of dropped packets
+    dropping: bool,
+}
+
+// Dropping the relay closes every queue, which ends the send tasks
+pub struct Relay {
+    targets: Vec<Target>,
+    // Empty relays everything
+    filters: Vec<Regex>,
+}
+
+impl Relay {
+    // None when there are no targets configured
+    pub async fn start(listen_addr: SocketAddr) -> Result<Option<Relay>, Box<dyn std::error::Error>> {
+        let relay_config = &config::get_config().relay;
+        let osc_config = &config::get_config().osc;
+        if relay_config.targets.is_empty() {
+            return Ok(None);
+        }
+
+        let mut filters = Vec::new();
+        for address in &relay_config.addresses {
+            let address = pattern::full_address(&osc_config.touchpoint_prefix, address);
+            filters.push(pattern::compile(&address).map_err(|e| format!("Bad relay address {}: {}", address, e))?);
+        }
+
+        let mut targets = Vec::new();
+        for target in &relay_config.targets {
+            let addr: SocketAddr = target.parse().map_err(|e| format!("Bad relay target {}: {}", target, e))?;
+            if addr.port() == listen_addr.port() && (addr.ip() == listen_addr.ip() || listen_addr.ip().is_unspecified()) {
+                log::warn!("Relay target {} is our own listen address, skipping it so packets don't loop", addr);
+                continue;
+            }
+
+            // We never read from these, the OS picks the port
+            let bind_addr: SocketAddr = if addr.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+            let socket = UdpSocket::bind(bind_addr).await?;
+            let (queue, packets) = mpsc::channel(relay_config.queue_size.max(1));
+            tokio::spawn(send_packets(socket, addr, packets));
+            targets.push(Target { addr, queue, dropping: false });
+        }
+
+        log::info!("Relaying OSC to {} target(s){}", targets.len(), if filters.is_empty() { String::new() } else { format!(", {} address filter(s)", filters.len()) });
+        Ok(Some(Relay { targets, filters }))
+    }
+
+    // Never waits, a full queue means that target is too slow and the packet is dropped for it only
+    // bytes is what was received, None when the packet was changed since and has to be encoded again
+    pub fn forward(&mut self, bytes: Option<&[u8]>, packet: &OscPacket) {
+        let bytes = match bytes {
+            Some(bytes) if self.filters.is_empty() => Arc::new(bytes.to_vec()),
+            _ => {
+                let filtered = if self.filters.is_empty() {
+                    packet.clone()
+                } else {
+                    match self.filter(packet) {
+                        Some(filtered) => filtered,
+                        None => return,
+                    }
+                };
+                match rosc::encoder::encode(&filtered) {
+                    Ok(bytes) => Arc::new(bytes),
+                    Err(e) => {
+                        log::error!("Failed to encode relayed OSC packet: {}", e);
+                        return;
+                    }
+                }
+            }
+        };
+
+        for target in &mut self.targets {
+            match target.queue.try_send(Arc::clone(&bytes)) {
+                Ok(()) => {
+                    if target.dropping {
+                        log::info!("Relay target {} caught up", target.addr);
+                        target.dropping = false;
+                    }
+                },
+                Err(TrySendError::Full(_)) => {
+                    if !target.dropping {
+                        log::warn!("Relay target {} is falling behind, dropping packets for it", target.addr);
+                        target.dropping = true;
+                    }
+                },
+                Err(TrySendError::Closed(_)) => log::trace!("Relay target {} is closed", target.addr),
+            }
+        }
+    }
+
+    // The parts of the packet the filters let through, bundles keep their timetag, None if nothing matched
+    fn filter(&self, packet: &OscPacket) -> Option<OscPacket> {
+        match packet {
+            OscPacket::Message(msg) => {
+                self.filters.iter().any(|filter| filter.is_match(&msg.addr)).then(|| packet.clone())
+            },
+            OscPacket::Bundle(bundle) => {
+                let content: Vec<OscPacket> = bundle.content.iter().filter_map(|packet| self.filter(packet)).collect();
+                (!content.is_empty()).then_some(OscPacket::Bundle(OscBundle { timetag: bundle.timetag, content }))
+            },
+        }
+    }
+}
+
+async fn send_packets(socket: UdpSocket, addr: SocketAddr, mut packets: mpsc::Receiver<Arc<Vec<u8>>>) {
+    // Nothing listening on the target shows up as send errors, only log the first of a run
+    let mut failing = false;
+    while let Some(bytes) = packets.recv().await {
+        match socket.send_to(&bytes, addr).await {
+            Ok(_) => failing = false,
+            Err(e) => {
+                if !failing {
+                    log::warn!("Failed to relay OSC to {}: {}", addr, e);
+                    failing = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rosc::{OscMessage, OscTime, OscType};
+
+    fn message(addr: &str) -> OscPacket {
+        OscPacket::Message(OscMessage { addr: addr.to_string(), args: vec![OscType::Float(0.5)] })
+    }
+
+    fn bundle(content: Vec<OscPacket>) -> OscPacket {
+        OscPacket::Bundle(OscBundle { timetag: OscTime { seconds: 1, fractional: 2 }, content })
+    }
+
+    // One target without a socket behind it, the test reads its queue
+    fn relay(filters: &[&str], queue_size: usize) -> (Relay, mpsc::Receiver<Arc<Vec<u8>>>) {
+        let (queue, packets) = mpsc::channel(queue_size);
+        let relay = Relay {
+            targets: vec![Target { addr: ([127, 0, 0, 1], 9002).into(), queue, dropping: false }],
+            filters: filters.iter().map(|filter| pattern::compile(filter).unwrap()).collect(),
+        };
+        (relay, packets)
+    }
+
+    fn decode(bytes: &[u8]) -> OscPacket {
+        rosc::decoder::decode_udp(bytes).unwrap().1
+    }
+
+    #[test]
+    fn filters_keep_only_matching_messages() {
+        let (relay, _packets) = relay(&["/avatar/change", "/avatar/parameters/FT/*"], 1);
+
+        assert_eq!(relay.filter(&message("/avatar/change")), Some(message("/avatar/change")));
+        assert_eq!(relay.filter(&message("/avatar/parameters/FT/JawOpen")), Some(message("/avatar/parameters/FT/JawOpen")));
+        assert_eq!(relay.filter(&message("/avatar/parameters/tail")), None);
+    }
+
+    #[test]
+    fn bundles_are_pruned_and_keep_their_timetag() {
+        let (relay, _packets) = relay(&["/avatar/parameters/FT/*"], 1);
+
+        let received = bundle(vec![
+            message("/avatar/parameters/tail"),
+            bundle(vec![message("/avatar/parameters/FT/JawOpen"), message("/avatar/parameters/NoseBoop")]),
+        ]);
+        assert_eq!(relay.filter(&received), Some(bundle(vec![bundle(vec![message("/avatar/parameters/FT/JawOpen")])])));
+
+        // Nothing left at all, nothing is relayed
+        assert_eq!(relay.filter(&bundle(vec![bundle(vec![message("/avatar/parameters/tail")])])), None);
+    }
+
+    #[test]
+    fn unfiltered_relay_sends_the_received_bytes() {
+        let (mut relay, mut packets) = relay(&[], 4);
+        let packet = message("/avatar/parameters/tail");
+        let bytes = rosc::encoder::encode(&packet).unwrap();
+
+        relay.forward(Some(&bytes), &packet);
+        assert_eq!(*packets.try_recv().unwrap(), bytes);
+
+        // Changed since it was received (EX: secret prefix stripped), so it is encoded again
+        relay.forward(None, &packet);
+        assert_eq!(decode(&packets.try_recv().unwrap()), packet);
+    }
+
+    #[test]
+    fn filtered_relay_encodes_what_is_left() {
+        let (mut relay, mut packets) = relay(&["/avatar/change"], 4);
+        let received = bundle(vec![message("/avatar/change"), message("/avatar/parameters/tail")]);
+        let bytes = rosc::encoder::encode(&received).unwrap();
+
+        relay.forward(Some(&bytes), &received);
+        assert_eq!(decode(&packets.try_recv().unwrap()), bundle(vec![message("/avatar/change")]));
+
+        relay.forward(None, &message("/avatar/parameters/tail"));
+        assert!(packets.try_recv().is_err());
+    }
+
+    #[test]
+    fn full_queue_drops_instead_of_waiting() {
+        let (mut relay, mut packets) = relay(&[], 1);
+        let packet = message("/avatar/parameters/tail");
+
+        relay.forward(None, &packet);
+        relay.forward(None, &packet);
+        assert!(relay.targets[0].dropping);
+
+        packets.try_recv().unwrap();
+        assert!(packets.try_recv().is_err());
+        relay.forward(None, &packet);
+        assert!(!relay.targets[0].dropping);
+    }
+}
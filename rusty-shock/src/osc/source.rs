@@ -0,0 +1,181 @@
+// Who may send us OSC, anyone who can reach listen_port could otherwise spoof avatar parameters and shock someone
+use crate::config;
+use rosc::{OscBundle, OscMessage, OscPacket};
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+// An IP or CIDR range from osc.allowed_sources, EX: "192.168.1.20" or "192.168.1.0/24"
+struct Range {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Range {
+    fn parse(range: &str) -> Result<Range, String> {
+        let (ip, prefix) = range.trim().split_once('/').unwrap_or((range.trim(), ""));
+        let written: IpAddr = ip.parse().map_err(|e| format!("{}: {}", range, e))?;
+        // Checked as IPv4 from here on, EX: ::ffff:192.168.1.0/120 is 192.168.1.0/24
+        let network = written.to_canonical();
+        let mapped = written.is_ipv6() && network.is_ipv4();
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = if prefix.is_empty() {
+            max_prefix
+        } else {
+            let prefix = prefix.parse::<u8>().map_err(|_| format!("{}: bad prefix length", range))?;
+            // The first 96 bits of a mapped address are the ::ffff: part
+            let prefix = if mapped {
+                prefix.checked_sub(96).ok_or(format!("{}: prefix length of an IPv4-mapped address has to be at least 96", range))?
+            } else {
+                prefix
+            };
+            if prefix > max_prefix {
+                return Err(format!("{}: bad prefix length", range));
+            }
+            prefix
+        };
+        Ok(Range { network, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        // The first prefix bits have to match
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+pub struct SourceFilter {
+    // Empty lets everyone through
+    allowed: Vec<Range>,
+    secret_prefix: String,
+    dropped: u64,
+    // Sources already warned about, the rest of their packets are only counted
+    warned: HashSet<IpAddr>,
+}
+
+impl SourceFilter {
+    pub fn new() -> Result<SourceFilter, String> {
+        let osc_config = &config::get_config().osc;
+        let allowed = osc_config.allowed_sources.iter()
+            .map(|range| Range::parse(range))
+            .collect::<Result<Vec<Range>, String>>()
+            .map_err(|e| format!("Bad osc.allowed_sources entry {}", e))?;
+        let secret_prefix = osc_config.secret_prefix.trim_end_matches('/').to_string();
+        if !secret_prefix.is_empty() && !secret_prefix.starts_with('/') {
+            return Err(format!("osc.secret_prefix has to start with /, got {}", secret_prefix));
+        }
+
+        if allowed.is_empty() {
+            log::warn!("osc.allowed_sources is empty, OSC from any address is accepted");
+        }
+        Ok(SourceFilter { allowed, secret_prefix, dropped: 0, warned: HashSet::new() })
+    }
+
+    // Should the raw packet from this source be decoded at all
+    pub fn allows(&mut self, source: IpAddr) -> bool {
+        if self.allowed.is_empty() || self.allowed.iter().any(|range| range.contains(source)) {
+            return true;
+        }
+        self.reject(source, "it is not in osc.allowed_sources");
+        false
+    }
+
+    // Loopback senders pass as is, everyone else has to put osc.secret_prefix in front of every address
+    // The prefix is stripped, None when nothing in the packet had it
+    pub fn unlock(&mut self, source: IpAddr, packet: OscPacket) -> Option<OscPacket> {
+        if !self.needs_secret(source) {
+            return Some(packet);
+        }
+        let unlocked = strip_prefix(&self.secret_prefix, packet);
+        if unlocked.is_none() {
+            self.reject(source, "its addresses are missing osc.secret_prefix");
+        }
+        unlocked
+    }
+
+    // When true unlock changes the packet, so it no longer matches the bytes received
+    pub fn needs_secret(&self, source: IpAddr) -> bool {
+        !self.secret_prefix.is_empty() && !source.to_canonical().is_loopback()
+    }
+
+    fn reject(&mut self, source: IpAddr, reason: &str) {
+        self.dropped += 1;
+        if self.warned.insert(source) {
+            log::warn!("Dropping OSC from {} because {}", source, reason);
+        }
+        log::debug!("Dropped OSC packet from {} ({} dropped so far)", source, self.dropped);
+    }
+}
+
+// Messages without the prefix are left out, empty bundles with them
+fn strip_prefix(prefix: &str, packet: OscPacket) -> Option<OscPacket> {
+    match packet {
+        OscPacket::Message(msg) => {
+            let addr = msg.addr.strip_prefix(prefix).filter(|addr| addr.starts_with('/'))?.to_string();
+            Some(OscPacket::Message(OscMessage { addr, args: msg.args }))
+        },
+        OscPacket::Bundle(bundle) => {
+            let content: Vec<OscPacket> = bundle.content.into_iter().filter_map(|packet| strip_prefix(prefix, packet)).collect();
+            (!content.is_empty()).then_some(OscPacket::Bundle(OscBundle { timetag: bundle.timetag, content }))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_range_matches_its_prefix() {
+        let range = Range::parse("192.168.1.0/24").unwrap();
+        assert!(range.contains(ip("192.168.1.20")));
+        assert!(range.contains(ip("::ffff:192.168.1.20")));
+        assert!(!range.contains(ip("192.168.2.20")));
+
+        let single = Range::parse("192.168.1.20").unwrap();
+        assert!(single.contains(ip("192.168.1.20")));
+        assert!(!single.contains(ip("192.168.1.21")));
+    }
+
+    #[test]
+    fn mapped_range_is_checked_as_ipv4() {
+        let range = Range::parse("::ffff:192.168.1.0/120").unwrap();
+        assert_eq!(range.prefix, 24);
+        assert!(range.contains(ip("192.168.1.20")));
+        assert!(!range.contains(ip("10.0.0.1")));
+
+        // Without a prefix it is the one address, not the whole IPv4 space
+        let single = Range::parse("::ffff:192.168.1.20").unwrap();
+        assert!(single.contains(ip("192.168.1.20")));
+        assert!(!single.contains(ip("192.168.1.21")));
+    }
+
+    #[test]
+    fn prefix_past_the_family_is_rejected() {
+        assert!(Range::parse("192.168.1.0/33").is_err());
+        assert!(Range::parse("::ffff:192.168.1.0/129").is_err());
+        assert!(Range::parse("::ffff:192.168.1.0/64").is_err());
+        assert!(Range::parse("fd00::/129").is_err());
+        assert!(Range::parse("192.168.1.0/abc").is_err());
+    }
+
+    #[test]
+    fn ipv6_range_matches_its_prefix() {
+        let range = Range::parse("fd00::/8").unwrap();
+        assert!(range.contains(ip("fd12::1")));
+        assert!(!range.contains(ip("fe80::1")));
+        assert!(!range.contains(ip("192.168.1.20")));
+    }
+}
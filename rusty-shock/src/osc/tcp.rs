@@ -0,0 +1,249 @@
+// OSC over TCP for controllers and bridges that don't do UDP
+// Packets from one connection are handled one after another, so they arrive in the order they were sent
+// OSC 1.1 frames packets with SLIP, OSC 1.0 puts a big endian int32 size in front of each one
+use crate::config;
+use crate::osc::osc::handle_packet;
+use crate::osc::parameters::ParameterStore;
+use crate::osc::source::SourceFilter;
+use crate::shutdown::Shutdown;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::time::{self, Duration};
+
+// Nothing we handle comes close, this stops a bad sender from making us buffer forever
+const MAX_PACKET_BYTES: usize = 64 * 1024;
+// A controller or bridge needs one connection, anything past this is refused
+const MAX_CONNECTIONS: usize = 8;
+// A connection that starts a packet and doesn't finish it in this long is closed, the sender can reconnect
+// Waiting for the next packet has no limit, a controller can stay connected and quiet for as long as it likes
+const FRAME_TIMEOUT: Duration = Duration::from_secs(120);
+
+// SLIP special bytes, RFC 1055
+const SLIP_END: u8 = 0xC0;
+const SLIP_ESC: u8 = 0xDB;
+const SLIP_ESC_END: u8 = 0xDC;
+const SLIP_ESC_ESC: u8 = 0xDD;
+
+#[derive(Clone, Copy, Debug)]
+enum Framing {
+    Slip,
+    LengthPrefix,
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(framing: &str) -> Result<Self, Self::Err> {
+        match framing.to_lowercase().as_str() {
+            "slip" => Ok(Framing::Slip),
+            "length" => Ok(Framing::LengthPrefix),
+            _ => Err(format!("Unknown osc.tcp_framing {}, expected slip or length", framing)),
+        }
+    }
+}
+
+// sources is the UDP server's filter, connections are ended when shutdown triggers
+pub async fn start_tcp_server(store: Arc<ParameterStore>, sources: Arc<Mutex<SourceFilter>>, shutdown: Shutdown) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let osc_config = &config::get_config().osc;
+    let framing: Framing = osc_config.tcp_framing.parse()?;
+    let bind_address = if osc_config.bind_address.is_empty() { &osc_config.ip_address } else { &osc_config.bind_address };
+    let listen_addr = SocketAddr::from((IpAddr::from_str(bind_address)?, osc_config.tcp_port));
+
+    let listener = TcpListener::bind(listen_addr).await?;
+    log::info!("OSC over TCP listening on {} ({:?} framing)", listener.local_addr()?, framing);
+
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    serve(listener, framing, store, sources, shutdown, connections, FRAME_TIMEOUT).await?;
+    Ok(())
+}
+
+async fn serve(
+    listener: TcpListener,
+    framing: Framing,
+    store: Arc<ParameterStore>,
+    sources: Arc<Mutex<SourceFilter>>,
+    shutdown: Shutdown,
+    connections: Arc<Semaphore>,
+    frame_timeout: Duration,
+) -> std::io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        if !sources.lock().expect("Source filter lock poisoned").allows(addr.ip()) {
+            continue;
+        }
+        // Held for as long as the connection is open
+        let permit = match Arc::clone(&connections).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                log::warn!("Refusing OSC TCP connection from {}, already at {} connections", addr, MAX_CONNECTIONS);
+                continue;
+            }
+        };
+        log::debug!("OSC TCP connection from {}", addr);
+
+        let store = Arc::clone(&store);
+        let sources = Arc::clone(&sources);
+        tokio::spawn(shutdown.wrap_cancel(async move {
+            match handle_connection(stream, addr, framing, &store, &sources, frame_timeout).await {
+                Ok(()) => log::debug!("OSC TCP connection from {} closed", addr),
+                Err(e) => log::warn!("OSC TCP connection from {} failed: {}", addr, e),
+            }
+            drop(permit);
+        }));
+    }
+}
+
+async fn handle_connection(stream: TcpStream, addr: SocketAddr, framing: Framing, store: &Arc<ParameterStore>, sources: &Mutex<SourceFilter>, frame_timeout: Duration) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    loop {
+        // The timeout only starts with the first byte of a packet, nothing to send isn't a reason to disconnect
+        if reader.fill_buf().await?.is_empty() {
+            return Ok(());
+        }
+        let frame = match framing {
+            Framing::Slip => time::timeout(frame_timeout, read_slip_frame(&mut reader)).await,
+            Framing::LengthPrefix => time::timeout(frame_timeout, read_length_frame(&mut reader)).await,
+        };
+        let frame = match frame {
+            Ok(frame) => frame?,
+            Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, format!("packet not finished within {} s", frame_timeout.as_secs()))),
+        };
+        let frame = match frame {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        if frame.is_empty() {
+            continue;
+        }
+
+        match rosc::decoder::decode_udp(&frame) {
+            Ok((_, packet)) => {
+                log::debug!("Received TCP packet with size {} from: {}", frame.len(), addr);
+                let packet = sources.lock().expect("Source filter lock poisoned").unlock(addr.ip(), packet);
+                if let Some(packet) = packet {
+                    handle_packet(packet, store);
+                }
+            },
+            Err(e) => log::error!("Failed to decode OSC packet from {}: {}", addr, e),
+        }
+    }
+}
+
+// Bytes up to the next END with escapes undone, None once the connection closes
+async fn read_slip_frame(reader: &mut BufReader<TcpStream>) -> std::io::Result<Option<Vec<u8>>> {
+    let mut escaped = Vec::new();
+    if (&mut *reader).take(MAX_PACKET_BYTES as u64 + 1).read_until(SLIP_END, &mut escaped).await? == 0 {
+        return Ok(None);
+    }
+    if escaped.last() == Some(&SLIP_END) {
+        escaped.pop();
+    } else if escaped.len() > MAX_PACKET_BYTES {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("SLIP frame over {} bytes", MAX_PACKET_BYTES)));
+    }
+
+    let mut frame = Vec::with_capacity(escaped.len());
+    let mut bytes = escaped.into_iter();
+    while let Some(byte) = bytes.next() {
+        if byte != SLIP_ESC {
+            frame.push(byte);
+            continue;
+        }
+        match bytes.next() {
+            Some(SLIP_ESC_END) => frame.push(SLIP_END),
+            Some(SLIP_ESC_ESC) => frame.push(SLIP_ESC),
+            other => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Bad SLIP escape {:?}", other))),
+        }
+    }
+    Ok(Some(frame))
+}
+
+// Big endian int32 size then the packet, None once the connection closes
+async fn read_length_frame(reader: &mut BufReader<TcpStream>) -> std::io::Result<Option<Vec<u8>>> {
+    let size = match reader.read_u32().await {
+        Ok(size) => size as usize,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if size > MAX_PACKET_BYTES {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Packet of {} bytes is over {}", size, MAX_PACKET_BYTES)));
+    }
+    let mut frame = vec![0u8; size];
+    reader.read_exact(&mut frame).await?;
+    Ok(Some(frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    async fn start(frame_timeout: Duration, max_connections: usize) -> (SocketAddr, Shutdown) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let sources = Arc::new(Mutex::new(SourceFilter::new().unwrap()));
+        let connections = Arc::new(Semaphore::new(max_connections));
+        tokio::spawn(serve(listener, Framing::Slip, Arc::new(ParameterStore::new()), sources, shutdown.clone(), connections, frame_timeout));
+        (addr, shutdown)
+    }
+
+    // Resolves once the server closes the connection
+    async fn closed(stream: &mut TcpStream) -> bool {
+        let mut buffer = [0u8; 16];
+        matches!(time::timeout(Duration::from_secs(2), stream.read(&mut buffer)).await, Ok(Ok(0)) | Ok(Err(_)))
+    }
+
+    #[tokio::test]
+    async fn unfinished_frame_is_closed() {
+        let (addr, _shutdown) = start(Duration::from_millis(50), MAX_CONNECTIONS).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        // Half a frame and then nothing
+        stream.write_all(b"/avatar").await.unwrap();
+        assert!(closed(&mut stream).await);
+    }
+
+    // A controller with nothing to say keeps its connection, and can still send afterwards
+    #[tokio::test]
+    async fn idle_connection_stays_open() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let store = Arc::new(ParameterStore::new());
+        let sources = Arc::new(Mutex::new(SourceFilter::new().unwrap()));
+        let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+        tokio::spawn(serve(listener, Framing::Slip, Arc::clone(&store), sources, Shutdown::new(), connections, Duration::from_millis(50)));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buffer = [0u8; 16];
+        assert!(time::timeout(Duration::from_millis(300), stream.read(&mut buffer)).await.is_err(), "idle connection was closed");
+
+        let packet = rosc::OscPacket::Message(rosc::OscMessage { addr: "/avatar/parameters/tail".to_string(), args: vec![rosc::OscType::Float(0.5)] });
+        let mut frame = rosc::encoder::encode(&packet).unwrap();
+        frame.push(SLIP_END);
+        stream.write_all(&frame).await.unwrap();
+        time::timeout(Duration::from_secs(2), store.changed()).await.expect("packet after the idle time never arrived");
+        assert_eq!(store.take().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn connections_past_the_cap_are_refused() {
+        let (addr, _shutdown) = start(Duration::from_secs(60), 1).await;
+        let _first = TcpStream::connect(addr).await.unwrap();
+        // Give the server a moment to take the only permit
+        time::sleep(Duration::from_millis(50)).await;
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert!(closed(&mut second).await);
+    }
+
+    #[tokio::test]
+    async fn shutdown_ends_open_connections() {
+        let (addr, shutdown) = start(Duration::from_secs(60), MAX_CONNECTIONS).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        let _ = shutdown.trigger_shutdown("test");
+        assert!(closed(&mut stream).await);
+    }
+}
@@ -2,19 +2,49 @@ use rosc::{OscType,OscMessage};
 use serde::Deserialize;
 use std::fs;
 use once_cell::sync::Lazy;
+use regex::Regex;
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use tokio::time::{Duration,self,Instant};
 use tokio::sync::Mutex;
 use tokio::sync::mpsc;
 use crate::WorldCommandEvent;
-use futures::future::FutureExt;
+use crate::backend::router;
+use crate::config;
+use crate::osc::cooldown::{self, CooldownPolicy, Trigger};
+use crate::osc::curves::Curve;
+use crate::osc::pattern;
+use crate::osc::physbone;
+use crate::osc::ramp::{self, Ramp};
+use crate::osc::velocity::{self, Impact, Motion};
+use crate::osc::parameters::ParameterStore;
+use crate::safety::estop;
 
 use crate::{openshock_legacy,openshock,pishock};
 
 #[derive(Deserialize)]
 pub struct Touchpoints {
+    // The default set, used until VRChat reports an avatar that has its own
+    #[serde(default)]
     pub touchpoints: Vec<Device>,
+    // Shocker ID -> milliseconds before that shocker can be triggered again, from any touchpoint
+    #[serde(default)]
+    pub shocker_cooldowns: HashMap<String, u64>,
+    // VRChat avatar ID -> that avatar's touchpoints, EX: [[avatars.avtr_xxx.touchpoints]]
+    #[serde(default)]
+    pub avatars: HashMap<String, AvatarTouchpoints>,
+}
+
+#[derive(Deserialize)]
+pub struct AvatarTouchpoints {
+    pub touchpoints: Vec<Device>,
+}
+
+impl Touchpoints {
+    // Touchpoints from every set, for anything that has to know about every shocker
+    pub fn all_devices(&self) -> impl Iterator<Item = &Device> {
+        self.touchpoints.iter().chain(self.avatars.values().flat_map(|avatar| avatar.touchpoints.iter()))
+    }
 }
 
 #[derive(Deserialize)]
@@ -24,6 +54,33 @@ pub struct Device {
     pub intensity: f32,
     pub duration: u64,
     pub ids: Vec<String>,
+    // Milliseconds after firing before this touchpoint can fire again, 0 is no cooldown
+    #[serde(default)]
+    pub cooldown: u64,
+    #[serde(default)]
+    pub cooldown_policy: CooldownPolicy,
+    // Maps the contact value to intensity, linear by default
+    #[serde(default)]
+    pub curve: Curve,
+    // Hold to escalate, the contact value only decides whether the touchpoint is held
+    pub ramp: Option<Ramp>,
+    // Fast approaches fire the highest impact reached instead of the normal intensity
+    #[serde(default)]
+    pub impacts: Vec<Impact>,
+    // Int parameter value -> fraction of intensity, EX: int_values = { "1" = 0.3, "2" = 1.0 }
+    #[serde(default)]
+    pub int_values: HashMap<String, f32>,
+    // What the avatar parameter is, only used to describe it over OSCQuery
+    // Unset is int with int_values, float otherwise, messages are always handled by the type they arrive with
+    pub parameter_type: Option<ParameterType>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParameterType {
+    Float,
+    Bool,
+    Int,
 }
 
 #[derive(Debug, Clone)]
@@ -47,9 +104,74 @@ pub fn get_config() -> &'static Touchpoints {
     &TOUCHPOINTS
 }
 
+// VRChat sends the new avatar ID here whenever the avatar changes
+pub const AVATAR_CHANGE: &str = "/avatar/change";
+
+// One set of touchpoints with every address as a full OSC address pattern, relative addresses get osc.touchpointPrefix in front
+pub struct TouchpointSet {
+    pub touchpoints: &'static [Device],
+    // Shared by every set, they come from the same touchpoints file
+    pub shocker_cooldowns: &'static HashMap<String, u64>,
+    patterns: Vec<(Regex, &'static Device)>,
+}
+
+impl TouchpointSet {
+    fn compile(touchpoints: &'static [Device]) -> TouchpointSet {
+        TouchpointSet::new(touchpoints, &TOUCHPOINTS.shocker_cooldowns, &config::get_config().osc.touchpoint_prefix)
+    }
+
+    pub(crate) fn new(touchpoints: &'static [Device], shocker_cooldowns: &'static HashMap<String, u64>, prefix: &str) -> TouchpointSet {
+        let patterns = touchpoints.iter().filter_map(|device| {
+            let address = pattern::full_address(prefix, &device.address);
+            match pattern::compile(&address) {
+                Ok(regex) => {
+                    log::debug!("Touchpoint {} matches {}", device.address, address);
+                    Some((regex, device))
+                },
+                Err(e) => {
+                    log::error!("Invalid touchpoint address {}: {}", device.address, e);
+                    None
+                }
+            }
+        }).collect();
+        TouchpointSet { touchpoints, shocker_cooldowns, patterns }
+    }
+
+    // The first touchpoint, in touchpoints.toml order, whose full address pattern matches
+    pub(crate) fn find(&self, message_addr: &str) -> Option<&'static Device> {
+        self.patterns.iter()
+            .find(|(pattern, _)| pattern.is_match(message_addr))
+            .map(|(_, device)| *device)
+    }
+
+    // The touchpoint with exactly this address as written in touchpoints.toml
+    fn get(&self, address: &str) -> Option<&'static Device> {
+        self.touchpoints.iter().find(|device| device.address == address)
+    }
+}
+
+static DEFAULT_SET: Lazy<TouchpointSet> = Lazy::new(|| TouchpointSet::compile(&TOUCHPOINTS.touchpoints));
+
+static AVATAR_SETS: Lazy<HashMap<&'static str, TouchpointSet>> = Lazy::new(|| {
+    TOUCHPOINTS.avatars.iter()
+        .map(|(avatar_id, avatar)| (avatar_id.as_str(), TouchpointSet::compile(&avatar.touchpoints)))
+        .collect()
+});
+
+// Avatar ID of the set in use, None is the default set
+static ACTIVE_AVATAR: std::sync::Mutex<Option<&'static str>> = std::sync::Mutex::new(None);
+
+fn touchpoint_set(avatar_id: Option<&str>) -> &'static TouchpointSet {
+    avatar_id.and_then(|avatar_id| AVATAR_SETS.get(avatar_id)).unwrap_or(&DEFAULT_SET)
+}
+
+pub fn active_set() -> &'static TouchpointSet {
+    touchpoint_set(*ACTIVE_AVATAR.lock().expect("Active avatar lock poisoned"))
+}
+
 pub async fn initialize_commandmap() -> Arc<Mutex<HashMap<String, CommandState>>> {
     let command_states: Arc<Mutex<HashMap<String, CommandState>>> = Arc::new(Mutex::new(HashMap::new()));
-    for device in TOUCHPOINTS.touchpoints.iter() {
+    for device in TOUCHPOINTS.all_devices() {
         for id in &device.ids {
             for &method in &device.method {
             // Convert the u32 id to a String based on the expected format in the rest of the code
@@ -74,7 +196,7 @@ pub async fn initialize_commandmap() -> Arc<Mutex<HashMap<String, CommandState>>
 
 
 pub async fn display_and_handle_touchpoints(
-    mut osc_rx: Option<mpsc::Receiver<OscMessage>>,
+    osc_parameters: Option<Arc<ParameterStore>>,
     mut world_command_rx: Option<mpsc::Receiver<WorldCommandEvent>>,
     command_states: Arc<Mutex<HashMap<String, CommandState>>>,
     delay_ms: u64
@@ -82,19 +204,43 @@ pub async fn display_and_handle_touchpoints(
 
     let interval = Duration::from_millis(delay_ms);
     let mut interval_timer = time::interval(interval);
+    let mut last_coalesced = 0;
+    log::info!("Using the default touchpoints ({}), {} avatar(s) have their own", DEFAULT_SET.touchpoints.len(), AVATAR_SETS.len());
 
     loop {
         tokio::select! {
             _ = interval_timer.tick() => {
                 // Regular interval tasks
+                if let Some(parameters) = &osc_parameters {
+                    let coalesced = parameters.coalesced_count();
+                    if coalesced != last_coalesced {
+                        log::debug!("OSC updates received: {}, coalesced: {}", parameters.received_count(), coalesced);
+                        last_coalesced = coalesced;
+                    }
+                }
+                handle_queued_triggers(Arc::clone(&command_states)).await;
+                handle_held_contacts(Arc::clone(&command_states)).await;
+                handle_held_physbones(Arc::clone(&command_states)).await;
             },
-            Some(message) = async { osc_rx.as_mut().and_then(|rx| rx.recv().now_or_never().flatten()) }, if osc_rx.is_some() => {
-                handle_osc_messages(message, Arc::clone(&command_states)).await;
+            // Sleeps until the OSC server stores a new value, then handles the newest value of every changed address
+            _ = async { osc_parameters.as_ref().expect("OSC branch enabled without a parameter store").changed().await }, if osc_parameters.is_some() => {
+                let parameters = osc_parameters.as_ref().expect("OSC branch enabled without a parameter store");
+                let mut messages = parameters.take();
+                // Switch avatars before anything else in the batch, the rest most likely came from the new avatar
+                messages.sort_by_key(|message| message.addr != AVATAR_CHANGE);
+                for message in messages {
+                    handle_osc_messages(message, Arc::clone(&command_states)).await;
+                }
             },
-            Some(event) = async { world_command_rx.as_mut().and_then(|rx| rx.recv().now_or_never().flatten()) }, if world_command_rx.is_some() => {
-                handle_world_command_messages(event, Arc::clone(&command_states)).await;
+            event = async { world_command_rx.as_mut().expect("World Command branch enabled without a receiver").recv().await }, if world_command_rx.is_some() => {
+                match event {
+                    Some(event) => handle_world_command_messages(event, Arc::clone(&command_states)).await,
+                    None => {
+                        log::warn!("World Command channel closed, no more world events will be handled");
+                        world_command_rx = None;
+                    }
+                }
             },
-            else => break,
         }
     }
 
@@ -104,30 +250,284 @@ pub async fn display_and_handle_touchpoints(
 
 
 async fn handle_osc_messages(message: OscMessage, command_map: Arc<Mutex<HashMap<String, CommandState>>>) {
-    let mut command_states = command_map.lock().await;
-    // ...
+    log::debug!("OSC Message: {} {:?}", message.addr, message.args);
+    // Still followed during an emergency stop so the right touchpoints are in use after re-arming
+    if message.addr == AVATAR_CHANGE {
+        change_avatar(&message, command_map).await;
+        return;
+    }
+    if estop::is_engaged() {
+        log::debug!("Emergency stop engaged, ignoring OSC message: {}", message.addr);
+        return;
+    }
+    // process_message takes the lock per shocker, so we don't hold it here
+    process_message(&message, active_set(), command_map).await;
 }
 
 async fn handle_world_command_messages(event: WorldCommandEvent, command_map: Arc<Mutex<HashMap<String, CommandState>>>) {
+    log::debug!("World Command Event: {:?}", event);
+    if estop::is_engaged() {
+        log::debug!("Emergency stop engaged, ignoring World Command for: {}", event.address);
+        return;
+    }
+
+    let device = match active_set().get(&event.address) {
+        Some(device) => device,
+        None => {
+            log::error!("World Command targets unknown touchpoint: {}", event.address);
+            return;
+        }
+    };
+
+    // The touchpoint config is the upper bound, a world can never ask for more than the avatar allows
+    let intensity = event.intensity.clamp(0.0, 1.0) * device.intensity;
+    let duration = event.duration.min(device.duration);
+    let expiry = Instant::now() + Duration::from_millis(duration);
+
+    // No method from the world means whatever the touchpoint allows
+    let methods = if event.method.is_empty() { &device.method } else { &event.method };
+
+    let shocker_ids: Vec<String> = methods.iter()
+        .filter(|method| device.method.contains(method))
+        .flat_map(|method| device.ids.iter().map(move |id| format!("{}_{}", id, method)))
+        .collect();
+    let trigger = Trigger {
+        address: pattern::full_address(&config::get_config().osc.touchpoint_prefix, &device.address),
+        shocker_ids,
+        intensity,
+        duration,
+        active_for: Duration::from_millis(duration),
+    };
+    let shocker_ids = cooldown::COOLDOWNS.lock().await.admit(device, trigger, &get_config().shocker_cooldowns, Instant::now());
+
+    for shocker_id in shocker_ids {
+        log::debug!("World Command updating shocker ID: {}", shocker_id);
+        process_shocker_by_id(shocker_id, command_map.clone(), expiry, duration, intensity).await;
+    }
+}
+
+// Send the queued triggers whose cooldown ran out
+async fn handle_queued_triggers(command_map: Arc<Mutex<HashMap<String, CommandState>>>) {
+    let mut cooldowns = cooldown::COOLDOWNS.lock().await;
+    if estop::is_engaged() {
+        // Nothing held back from before the emergency stop should fire after re-arming
+        cooldowns.clear_queue();
+        return;
+    }
+
+    let now = Instant::now();
+    for trigger in cooldowns.take_ready(now) {
+        let device = match find_touchpoint(&trigger.address) {
+            Some(device) => device,
+            None => continue,
+        };
+        log::debug!("Sending queued trigger for {}", trigger.address);
+        let (expiry, duration, intensity) = (now + trigger.active_for, trigger.duration, trigger.intensity);
+        for shocker_id in cooldowns.admit(device, trigger, &get_config().shocker_cooldowns, now) {
+            process_shocker_by_id(shocker_id, command_map.clone(), expiry, duration, intensity).await;
+        }
+    }
+}
+
+// Re-send every held ramp touchpoint, VRChat only sends a contact value when it changes
+async fn handle_held_contacts(command_map: Arc<Mutex<HashMap<String, CommandState>>>) {
+    let now = Instant::now();
+    let mut contacts = ramp::CONTACTS.lock().await;
+    if estop::is_engaged() {
+        // Start from the beginning after re-arming
+        contacts.clear();
+        return;
+    }
+
+    for address in contacts.held(now) {
+        let device = match find_touchpoint(&address) {
+            Some(device) => device,
+            None => continue,
+        };
+        let ramp = match &device.ramp {
+            Some(ramp) => ramp,
+            None => continue,
+        };
+        // Don't overwrite a short impact hit that is still running
+        if velocity::SAMPLES.lock().await.in_impact(&address, now) {
+            continue;
+        }
+        let intensity = contacts.level(&address, ramp, now) * device.intensity;
+        log::trace!("Ramp {} at intensity {}", address, intensity);
+        fire_touchpoint(active_set(), device, &address, touchpoint_shocker_ids(device), intensity, 50, Duration::from_millis(device.duration), command_map.clone()).await;
+    }
+}
+
+// Keep grabbed/posed PhysBone touchpoints active, VRChat sends IsGrabbed once when it changes
+async fn handle_held_physbones(command_map: Arc<Mutex<HashMap<String, CommandState>>>) {
+    let mut held = physbone::HELD.lock().await;
+    if estop::is_engaged() {
+        // Grabbing again after re-arming is needed to start it back up
+        held.clear();
+        return;
+    }
+
+    let now = Instant::now();
+    for address in held.held(now) {
+        let device = match find_touchpoint(&address) {
+            Some(device) => device,
+            None => continue,
+        };
+        // Ramps already re-send held touchpoints themselves
+        if device.ramp.is_some() || velocity::SAMPLES.lock().await.in_impact(&address, now) {
+            continue;
+        }
+        let intensity = device.curve.apply(1.0) * device.intensity;
+        fire_touchpoint(active_set(), device, &address, touchpoint_shocker_ids(device), intensity, 50, Duration::from_millis(device.duration), command_map.clone()).await;
+    }
+}
+
+// Switch to the touchpoints of the new avatar, or the default set if it has none
+async fn change_avatar(msg: &OscMessage, command_map: Arc<Mutex<HashMap<String, CommandState>>>) {
+    let avatar_id = match msg.args.first() {
+        Some(OscType::String(avatar_id)) => avatar_id,
+        other => {
+            log::warn!("{} with an unexpected argument {:?}, keeping the current touchpoints", AVATAR_CHANGE, other);
+            return;
+        }
+    };
+
+    let next = AVATAR_SETS.get_key_value(avatar_id.as_str()).map(|(avatar_id, _)| *avatar_id);
+    let previous = std::mem::replace(&mut *ACTIVE_AVATAR.lock().expect("Active avatar lock poisoned"), next);
+    if previous == next {
+        log::debug!("Avatar changed to {}, touchpoints stay the same", avatar_id);
+        return;
+    }
+    let (old_set, new_set) = (touchpoint_set(previous), touchpoint_set(next));
+    match next {
+        Some(_) => log::info!("Avatar changed to {}, using its {} touchpoint(s)", avatar_id, new_set.touchpoints.len()),
+        None => log::info!("Avatar changed to {}, it has no touchpoints of its own so the default set is used", avatar_id),
+    }
+
+    clear_removed_touchpoints(old_set, new_set, &command_map).await;
+}
+
+// Stop whatever touchpoints from the old set were still doing, unless the new set has the same touchpoint on the same shocker
+async fn clear_removed_touchpoints(old_set: &TouchpointSet, new_set: &TouchpointSet, command_map: &Arc<Mutex<HashMap<String, CommandState>>>) {
+    let kept: HashSet<String> = new_set.touchpoints.iter()
+        .filter(|device| old_set.get(&device.address).is_some())
+        .flat_map(touchpoint_shocker_ids)
+        .collect();
+
+    let mut stale: HashSet<String> = HashSet::new();
+    for device in old_set.touchpoints {
+        stale.extend(touchpoint_shocker_ids(device).into_iter().filter(|shocker_id| !kept.contains(shocker_id)));
+    }
+
+    // Ramps, impacts, grabbed bones, cooldowns and queued triggers are kept by the address VRChat sent
+    // Anything whose address no longer belongs to the same touchpoint would otherwise linger
+    let same_touchpoint = |address: &str| match (old_set.find(address), new_set.find(address)) {
+        (Some(old), Some(new)) => old.address == new.address,
+        _ => false,
+    };
+    ramp::CONTACTS.lock().await.retain(same_touchpoint);
+    velocity::SAMPLES.lock().await.retain(same_touchpoint);
+    physbone::HELD.lock().await.retain(same_touchpoint);
+    cooldown::COOLDOWNS.lock().await.retain_queued(same_touchpoint);
+
+    let now = Instant::now();
     let mut command_states = command_map.lock().await;
-    // ...
+    let mut cleared: HashSet<String> = HashSet::new();
+    for shocker_id in stale {
+        if let Some(command_state) = command_states.get_mut(&shocker_id).filter(|command_state| command_state.expiry > now) {
+            command_state.intensity = 0.0;
+            command_state.duration = 0;
+            command_state.expiry = now;
+            // Command map keys are "{id}_{method}", the backends stop the whole shocker
+            cleared.insert(shocker_id.rsplit_once('_').map(|(id, _)| id).unwrap_or(&shocker_id).to_string());
+        }
+    }
+    if !cleared.is_empty() {
+        log::info!("Stopping {} shocker(s) driven by touchpoints the new avatar doesn't have", cleared.len());
+        // Zeroing the commands only stops resending them, whatever is already running on the hardware has to be stopped too
+        router::request_stop(cleared);
+    }
 }
 
 // Helper function to process each message
-async fn process_message(msg: &OscMessage,commandmap: Arc<Mutex<HashMap<String, CommandState>>>,) {
+// set is the touchpoint set in use, normally active_set()
+pub(crate) async fn process_message(msg: &OscMessage, set: &TouchpointSet, commandmap: Arc<Mutex<HashMap<String, CommandState>>>,) {
+    if physbone::Suffix::from_address(&msg.addr).is_some() {
+        // Even a parameter without a touchpoint shows the bone is still being moved
+        physbone::HELD.lock().await.seen(&msg.addr, Instant::now());
+    }
+    // Most of what VRChat sends has nothing to do with touchpoints, so this stays quiet
+    let device = match set.find(&msg.addr) {
+        Some(device) => device,
+        None => {
+            log::trace!("No touchpoint for {}", msg.addr);
+            return;
+        }
+    };
     // Get all the potential shocker IDs
-    let shocker_ids = extract_shocker_ids(msg.addr.clone()).await;
+    let shocker_ids = extract_shocker_ids(device);
     log::debug!("Shocker IDs: {:?}", shocker_ids);
 
-    let shocker_intensity = extract_shocker_intensity(msg.addr.clone()).await;
-    // Safely attempt to extract the float value from the first argument.
-    // This is an unsafe assumption of how OSC will output from VRChat
-    let intensity = match msg.args[0] {
-        OscType::Float(val) => val * shocker_intensity,
-        _ => {
-            log::error!("First touchpoint argument is not a float; handler will not proceed.");
-            return;
+    let shocker_intensity = extract_shocker_intensity(device);
+    // PhysBone parameters each have their own type, everything else follows the OSC argument type
+    // val is the raw value for impact detection, contact is what drives the intensity
+    let (val, contact) = match physbone::Suffix::from_address(&device.address) {
+        Some(suffix) => match suffix.value(msg.args.first()) {
+            Some(val) => {
+                if suffix.is_state() {
+                    physbone::HELD.lock().await.set(&msg.addr, val > 0.0, Instant::now());
+                }
+                (val, device.curve.apply(val))
+            },
+            None => {
+                log::error!("PhysBone parameter {} has an unexpected argument {:?}; handler will not proceed.", msg.addr, msg.args.first());
+                return;
+            }
+        },
+        None => match msg.args.first() {
+            // Proximity contacts
+            Some(OscType::Float(val)) => (*val, device.curve.apply(*val)),
+            // Constant contacts, touching is the full configured intensity
+            Some(OscType::Bool(state)) => {
+                let val = if *state { 1.0 } else { 0.0 };
+                (val, val)
+            },
+            Some(OscType::Int(value)) => match int_contact(device, *value) {
+                Some(val) => (val, val),
+                None => {
+                    log::warn!("Touchpoint {} has no int_values entry for {}; handler will not proceed.", device.address, value);
+                    return;
+                }
+            },
+            Some(other) => {
+                log::error!("Touchpoint argument {:?} is not a float, bool or int; handler will not proceed.", other);
+                return;
+            },
+            None => {
+                log::warn!("Touchpoint message {} has no arguments; handler will not proceed.", msg.addr);
+                return;
+            }
+        },
+    };
+
+    if !device.impacts.is_empty() {
+        let motion = velocity::SAMPLES.lock().await.update(&msg.addr, val, &device.impacts, Instant::now());
+        match motion {
+            Motion::Impact(impact) => {
+                let intensity = impact.intensity.clamp(0.0, 1.0) * shocker_intensity;
+                log::debug!("Impact intensity: {} for {} ms", intensity, impact.duration);
+                let active_for = Duration::from_millis(impact.duration);
+                fire_touchpoint(set, device, &msg.addr, shocker_ids, intensity, impact.duration, active_for, commandmap).await;
+                return;
+            },
+            Motion::Suppressed => return,
+            Motion::Normal => {},
         }
+    }
+
+    let intensity = match &device.ramp {
+        Some(ramp) => ramp::CONTACTS.lock().await.update(&msg.addr, ramp, contact > 0.0, Instant::now()) * shocker_intensity,
+        None => contact * shocker_intensity,
     };
     log::debug!("Intensity: {}", intensity);
 
@@ -137,38 +537,67 @@ async fn process_message(msg: &OscMessage,commandmap: Arc<Mutex<HashMap<String,
     let duration = 50;
     log::debug!("Duration: {}", duration);
 
+    fire_touchpoint(set, device, &msg.addr, shocker_ids, intensity, duration, Duration::from_millis(device.duration), commandmap).await;
+}
 
-    // Process each shocker ID
-    for shocker_id in shocker_ids {
-        process_shocker_by_id(shocker_id, msg, commandmap.clone(), duration, intensity).await;
+// Int parameters go through the touchpoint's int_values table, 0 is always a release
+fn int_contact(device: &Device, value: i32) -> Option<f32> {
+    match device.int_values.get(&value.to_string()) {
+        Some(contact) => Some(contact.clamp(0.0, 1.0)),
+        None if value == 0 => Some(0.0),
+        None => None,
     }
 }
 
-async fn extract_shocker_intensity(message_addr: String) -> f32 {
-    let message_addr = match message_addr.split("/").last() {
-        Some(addr) => addr,
-        None => { 
-            log::error!("[extract intensity]: Failed to extract Address {}", message_addr);
-            return 0.0;
-        },
+// Update the command map for a touchpoint, the command stays active for active_for
+// address is the one VRChat sent, cooldowns are per matching address rather than per touchpoint pattern
+#[allow(clippy::too_many_arguments)]
+async fn fire_touchpoint(set: &TouchpointSet, device: &Device, address: &str, shocker_ids: Vec<String>, intensity: f32, duration: u64, active_for: Duration, commandmap: Arc<Mutex<HashMap<String, CommandState>>>) {
+    let trigger = Trigger {
+        address: address.to_string(),
+        shocker_ids,
+        intensity,
+        duration,
+        active_for,
     };
-    log::debug!("Extracting shocker IDs from message address: {}", message_addr);
+    // Anything still cooling down is left out
+    let shocker_ids = cooldown::COOLDOWNS.lock().await.admit(device, trigger, set.shocker_cooldowns, Instant::now());
+    let expiry = Instant::now() + active_for;
 
-    if let Some(device) = TOUCHPOINTS.touchpoints.iter().find(|device| device.address == message_addr) {
-        device.intensity
-    } else {
-        log::error!("Unknown touchpoint: {}", message_addr);
-        0.0
+    // Process each shocker ID
+    for shocker_id in shocker_ids {
+        process_shocker_by_id(shocker_id, commandmap.clone(), expiry, duration, intensity).await;
     }
 }
 
-async fn process_shocker_by_id(shocker_id: String,message: &OscMessage,command_map: Arc<Mutex<HashMap<String, CommandState>>>, duration: u64,intensity: f32,) {
+// Command map keys for every shocker and method of a touchpoint
+fn touchpoint_shocker_ids(device: &Device) -> Vec<String> {
+    device.ids.iter().flat_map(|id| {
+        device.method.iter().map(move |&method| format!("{}_{}", id, method))
+    }).collect()
+}
+
+// The first touchpoint of the active set whose full address pattern matches
+fn find_touchpoint(message_addr: &str) -> Option<&'static Device> {
+    active_set().find(message_addr)
+}
+
+fn extract_shocker_intensity(device: &Device) -> f32 {
+    log::debug!("Extracting shocker intensity from touchpoint: {}", device.address);
+    device.intensity
+}
+
+async fn process_shocker_by_id(shocker_id: String,command_map: Arc<Mutex<HashMap<String, CommandState>>>, expiry: Instant, duration: u64,intensity: f32,) {
     log::debug!("Processing shocker ID: {}", shocker_id);
     let mut command_states = command_map.lock().await;
+    // Checked under the lock, an emergency stop engaged since the caller looked has already zeroed the map
+    if estop::is_engaged() {
+        return;
+    }
     // If the command state for this ID exists, update it
     if let Some(command_state) = command_states.get_mut(&shocker_id) {
         command_state.last_issued = Instant::now();
-        command_state.expiry = calculate_expiry(message.addr.clone()).await;
+        command_state.expiry = expiry;
         command_state.intensity = intensity;
         command_state.duration = duration;
     } else {
@@ -178,51 +607,14 @@ async fn process_shocker_by_id(shocker_id: String,message: &OscMessage,command_m
             duration,
             intensity,
             last_issued: Instant::now(),
-            expiry: calculate_expiry(message.addr.clone()).await,
+            expiry,
         };
         command_states.insert(shocker_id, new_command_state); // Now it's expecting a String key
     }
     // Release the lock automatically when it goes out of scope
 }
 
-async fn extract_shocker_ids(message_addr: String) -> Vec<String> {
-    let message_addr = match message_addr.split("/").last() {
-        Some(addr) => addr,
-        None => { 
-            log::error!("Failed to extract shocker IDs from message address: {}", message_addr);
-            return Vec::new();
-        },
-    };
-    log::debug!("Extracting shocker IDs from message address: {}", message_addr);
-
-    if let Some(device) = TOUCHPOINTS.touchpoints.iter().find(|device| device.address == message_addr) {
-        device.ids.iter().flat_map(|id| {
-            device.method.iter().map(move |&method| format!("{}_{}", id, method))
-        }).collect()
-    } else {
-        log::error!("Unknown touchpoint: {}", message_addr);
-        Vec::new()
-    }
-}
-
-
-
-async fn calculate_expiry(message_addr: String) -> Instant {
-    let message_addr = match message_addr.split("/").last() {
-        Some(addr) => addr,
-        None => { 
-            //log::error!("Failed to extract shocker IDs from message address: {}", message_addr);
-            return Instant::now();
-        },
-    };
-    log::debug!("Extracting shocker expiry from message address: {}", message_addr);
-    if let Some(device) = TOUCHPOINTS.touchpoints.iter().find(|device| device.address == message_addr) {
-        
-        let duration =Duration::from_millis(device.duration);
-        log::debug!("Duration: {:?}", duration);
-        Instant::now() + duration
-    } else {
-        log::error!("Unknown touchpoint: {}", message_addr);
-        Instant::now()
-    }
+fn extract_shocker_ids(device: &Device) -> Vec<String> {
+    log::debug!("Extracting shocker IDs from touchpoint: {}", device.address);
+    touchpoint_shocker_ids(device)
 }
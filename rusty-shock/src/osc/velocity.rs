@@ -0,0 +1,127 @@
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+// Contacts report distance, so a slap and a slow press can end at the same value
+// How fast the value climbed tells them apart
+// In touchpoints.toml: impacts = [{ velocity = 4.0, intensity = 0.6, duration = 150 }, { velocity = 8.0, intensity = 1.0, duration = 100 }]
+#[derive(Deserialize, Debug, Clone)]
+pub struct Impact {
+    // Contact value per second, EX: 4.0 is going from nothing to full contact in a quarter second
+    pub velocity: f32,
+    // Fraction of the touchpoint intensity
+    pub intensity: f32,
+    // Milliseconds, usually shorter than the touchpoint duration
+    #[serde(default = "default_impact_duration")]
+    pub duration: u64,
+}
+
+fn default_impact_duration() -> u64 {
+    100
+}
+
+// VRChat only sends a value when it changes, the last one can be from long before the approach started
+const MAX_SAMPLE_GAP: Duration = Duration::from_millis(100);
+// Two values arriving back to back would make any change look like a slap
+const MIN_SAMPLE_GAP: Duration = Duration::from_millis(10);
+
+pub enum Motion<'a> {
+    // Fast approach, fire this instead of the normal intensity
+    Impact(&'a Impact),
+    // Inside the window of an impact that already fired, leave it alone
+    Suppressed,
+    Normal,
+}
+
+struct Sample {
+    value: f32,
+    at: Instant,
+    impact_until: Option<Instant>,
+}
+
+// Address VRChat sent -> last contact value, each contact matching a wildcard touchpoint has its own speed
+pub static SAMPLES: Lazy<Mutex<Samples>> = Lazy::new(|| Mutex::new(Samples {
+    samples: HashMap::new(),
+}));
+
+pub struct Samples {
+    samples: HashMap<String, Sample>,
+}
+
+impl Samples {
+    // Record a contact value and check whether it came in fast enough to be an impact
+    pub fn update<'a>(&mut self, address: &str, value: f32, impacts: &'a [Impact], now: Instant) -> Motion<'a> {
+        let previous = self.samples.insert(address.to_string(), Sample { value, at: now, impact_until: None });
+        let previous = match previous {
+            Some(previous) => previous,
+            None => return Motion::Normal,
+        };
+
+        // Releasing or settling right after a slap would cut the short hit off before it is even sent
+        if let Some(impact_until) = previous.impact_until.filter(|&impact_until| impact_until > now) {
+            if let Some(sample) = self.samples.get_mut(address) {
+                sample.impact_until = Some(impact_until);
+            }
+            return Motion::Suppressed;
+        }
+
+        let elapsed = now.saturating_duration_since(previous.at).clamp(MIN_SAMPLE_GAP, MAX_SAMPLE_GAP);
+        let velocity = (value - previous.value) / elapsed.as_secs_f32();
+        if velocity <= 0.0 {
+            return Motion::Normal;
+        }
+
+        let impact = impacts.iter()
+            .filter(|impact| velocity >= impact.velocity)
+            .max_by(|a, b| a.velocity.total_cmp(&b.velocity));
+        match impact {
+            Some(impact) => {
+                log::debug!("Impact on {} at {:.2}/s, threshold {:.2}/s", address, velocity, impact.velocity);
+                if let Some(sample) = self.samples.get_mut(address) {
+                    sample.impact_until = Some(now + Duration::from_millis(impact.duration));
+                }
+                Motion::Impact(impact)
+            },
+            None => {
+                log::trace!("Contact {} approaching at {:.2}/s", address, velocity);
+                Motion::Normal
+            }
+        }
+    }
+
+    pub fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        self.samples.retain(|address, _| keep(address));
+    }
+
+    pub fn in_impact(&self, address: &str, now: Instant) -> bool {
+        self.samples.get(address)
+            .and_then(|sample| sample.impact_until)
+            .is_some_and(|impact_until| impact_until > now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contacts_of_one_touchpoint_have_their_own_speed() {
+        let impacts = [Impact { velocity: 4.0, intensity: 1.0, duration: 100 }];
+        let mut samples = Samples { samples: HashMap::new() };
+        let now = Instant::now();
+
+        samples.update("/avatar/parameters/HandLeft", 0.0, &impacts, now);
+        samples.update("/avatar/parameters/HandRight", 0.9, &impacts, now);
+        // Left slowly closing in, right resting, neither should look like a slap
+        let later = now + Duration::from_millis(50);
+        assert!(matches!(samples.update("/avatar/parameters/HandLeft", 0.1, &impacts, later), Motion::Normal));
+        assert!(matches!(samples.update("/avatar/parameters/HandRight", 0.9, &impacts, later), Motion::Normal));
+        // A real slap on the left still is one
+        let slap = later + Duration::from_millis(50);
+        assert!(matches!(samples.update("/avatar/parameters/HandLeft", 0.9, &impacts, slap), Motion::Impact(_)));
+        assert!(samples.in_impact("/avatar/parameters/HandLeft", slap));
+        assert!(!samples.in_impact("/avatar/parameters/HandRight", slap));
+    }
+}
@@ -0,0 +1,207 @@
+// PiShock HTTP API, https://apidocs.pishock.com/
+// Every answer is a 200 with a plain text message, so the text decides success or failure
+use crate::backend::BackendError;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tokio::time::Duration;
+
+// PiShock Op values
+pub const OP_SHOCK: u8 = 0;
+pub const OP_VIBRATE: u8 = 1;
+pub const OP_BEEP: u8 = 2;
+
+// PiShock only takes whole seconds
+pub const MIN_DURATION_SECS: u8 = 1;
+pub const MAX_DURATION_SECS: u8 = 15;
+
+// 0 is rejected, there is no way to send "nothing"
+pub const MIN_INTENSITY: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PiShockError {
+    NotAuthorized,
+    CodeNotFound,
+    ShockerPaused,
+    DeviceOffline,
+    IntensityOutOfRange(String),
+    DurationOutOfRange(String),
+    OperationNotAllowed(String),
+    UnknownOp,
+    Unknown(String),
+}
+
+impl PiShockError {
+    // Map the PiShock response text to a typed result
+    pub fn from_response(text: &str) -> Result<(), PiShockError> {
+        let text = text.trim();
+        match text {
+            "Operation Succeeded." | "Operation Attempted." => Ok(()),
+            "Not Authorized." => Err(PiShockError::NotAuthorized),
+            "This code doesn't exist." => Err(PiShockError::CodeNotFound),
+            "Shocker is Paused or does not exist. Unpause to send command." => Err(PiShockError::ShockerPaused),
+            "Device currently not connected." => Err(PiShockError::DeviceOffline),
+            _ if text.starts_with("Intensity must be between") => Err(PiShockError::IntensityOutOfRange(text.to_string())),
+            _ if text.starts_with("Duration must be between") => Err(PiShockError::DurationOutOfRange(text.to_string())),
+            _ if text.ends_with("not allowed.") || text.ends_with("not allowed") => Err(PiShockError::OperationNotAllowed(text.to_string())),
+            _ if text.starts_with("Unknown Op") => Err(PiShockError::UnknownOp),
+            _ => Err(PiShockError::Unknown(text.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for PiShockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PiShockError::NotAuthorized => write!(f, "PiShock: not authorized, check the username and api_authtoken"),
+            PiShockError::CodeNotFound => write!(f, "PiShock: share code doesn't exist"),
+            PiShockError::ShockerPaused => write!(f, "PiShock: shocker is paused or does not exist"),
+            PiShockError::DeviceOffline => write!(f, "PiShock: device is not connected"),
+            PiShockError::IntensityOutOfRange(e) => write!(f, "PiShock: {}", e),
+            PiShockError::DurationOutOfRange(e) => write!(f, "PiShock: {}", e),
+            PiShockError::OperationNotAllowed(e) => write!(f, "PiShock: {}", e),
+            PiShockError::UnknownOp => write!(f, "PiShock: unknown operation"),
+            PiShockError::Unknown(e) => write!(f, "PiShock: unexpected response: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PiShockError {}
+
+impl From<PiShockError> for BackendError {
+    fn from(e: PiShockError) -> Self {
+        match e {
+            PiShockError::NotAuthorized => BackendError::Unauthorized(e.to_string()),
+            PiShockError::DeviceOffline => BackendError::Connection(e.to_string()),
+            _ => BackendError::Rejected(e.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct OperateRequest<'a> {
+    username: &'a str,
+    name: &'a str,
+    code: &'a str,
+    intensity: u8,
+    duration: u8,
+    apikey: &'a str,
+    op: u8,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ShockerInfoRequest<'a> {
+    username: &'a str,
+    apikey: &'a str,
+    code: &'a str,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ShockerInfo {
+    pub name: String,
+    pub paused: bool,
+    pub online: bool,
+}
+
+pub struct PiShockApi {
+    client: Client,
+    api_url: String,
+    username: String,
+    api_key: String,
+    name: String,
+}
+
+impl PiShockApi {
+    pub fn new(api_url: &str, username: &str, api_key: &str, name: &str) -> Result<PiShockApi, BackendError> {
+        let client = Client::builder()
+            .user_agent(concat!("ShockRS/", env!("CARGO_PKG_VERSION")))
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| BackendError::Connection(e.to_string()))?;
+
+        Ok(PiShockApi {
+            client,
+            api_url: api_url.trim_end_matches('/').to_string(),
+            username: username.to_string(),
+            api_key: api_key.to_string(),
+            name: name.to_string(),
+        })
+    }
+
+    // POST /apioperate, intensity 1 - 100 and duration in whole seconds
+    pub async fn operate(&self, code: &str, op: u8, intensity: u8, duration_secs: u8) -> Result<(), BackendError> {
+        let body = OperateRequest {
+            username: &self.username,
+            name: &self.name,
+            code,
+            intensity,
+            duration: duration_secs,
+            apikey: &self.api_key,
+            op,
+        };
+        let response = self.client.post(format!("{}/apioperate", self.api_url))
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| BackendError::Connection(e.to_string()))?;
+
+        if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(BackendError::RateLimited(None));
+        }
+        let text = response.text().await.map_err(|e| BackendError::Connection(e.to_string()))?;
+        log::debug!("PiShock response for {}: {}", code, text);
+        PiShockError::from_response(&text)?;
+        Ok(())
+    }
+
+    // POST /GetShockerInfo
+    pub async fn shocker_info(&self, code: &str) -> Result<ShockerInfo, BackendError> {
+        let body = ShockerInfoRequest {
+            username: &self.username,
+            apikey: &self.api_key,
+            code,
+        };
+        let response = self.client.post(format!("{}/GetShockerInfo", self.api_url))
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| BackendError::Connection(e.to_string()))?;
+        let text = response.text().await.map_err(|e| BackendError::Connection(e.to_string()))?;
+
+        // Errors come back as the same plain text messages as apioperate
+        serde_json::from_str(&text).map_err(|_| match PiShockError::from_response(&text) {
+            Err(e) => e.into(),
+            Ok(()) => BackendError::Rejected(format!("Unexpected PiShock shocker info: {}", text)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_text_maps_to_errors() {
+        assert_eq!(PiShockError::from_response("Operation Succeeded."), Ok(()));
+        assert_eq!(PiShockError::from_response("Operation Attempted.\n"), Ok(()));
+        assert_eq!(PiShockError::from_response("Not Authorized."), Err(PiShockError::NotAuthorized));
+        assert_eq!(PiShockError::from_response("This code doesn't exist."), Err(PiShockError::CodeNotFound));
+        assert_eq!(PiShockError::from_response("Device currently not connected."), Err(PiShockError::DeviceOffline));
+        assert!(matches!(PiShockError::from_response("Intensity must be between 0 and 100"), Err(PiShockError::IntensityOutOfRange(_))));
+        assert!(matches!(PiShockError::from_response("Duration must be between 1 and 15"), Err(PiShockError::DurationOutOfRange(_))));
+        assert!(matches!(PiShockError::from_response("Shock not allowed."), Err(PiShockError::OperationNotAllowed(_))));
+        assert_eq!(PiShockError::from_response("Unknown Op, use 0 for shock, 1 for vibrate and 2 for beep"), Err(PiShockError::UnknownOp));
+        assert!(matches!(PiShockError::from_response("Something new"), Err(PiShockError::Unknown(_))));
+    }
+
+    #[test]
+    fn errors_map_to_backend_errors() {
+        assert!(matches!(BackendError::from(PiShockError::NotAuthorized), BackendError::Unauthorized(_)));
+        assert!(matches!(BackendError::from(PiShockError::DeviceOffline), BackendError::Connection(_)));
+        assert!(matches!(BackendError::from(PiShockError::ShockerPaused), BackendError::Rejected(_)));
+        assert!(matches!(BackendError::from(PiShockError::Unknown("?".to_string())), BackendError::Rejected(_)));
+    }
+}
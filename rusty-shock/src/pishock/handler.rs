@@ -1,9 +1,246 @@
-use rosc::OscType;
+use crate::backend::{BackendError, BackendHealth, Sent, ShockerBackend, ShockerCommand, ShockerInfo, METHOD_SHOCK, METHOD_VIBRATE, METHOD_SOUND};
+use crate::config;
+use crate::pishock::api::{self, PiShockApi};
+use async_trait::async_trait;
+use futures::future::join_all;
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
 
-pub async fn handler(touchpoint: String,touchpoint_args: Vec<OscType>) {
-    log::debug!("PiShock Touchpoint Handler");
-    log::warn!("unimplemented API");
-    for arg in touchpoint_args {
-        log::debug!("Touchpoint Argument: {:?}", arg);
+pub struct PiShockBackend {
+    api: PiShockApi,
+    // Touchpoint ID -> share code, from [pishock] in config.toml
+    share_codes: HashMap<String, String>,
+    // What each share code and op was last told to do and until when
+    // PiShock works in whole seconds, so a held contact would otherwise queue up a new operation every router pass
+    running: Mutex<HashMap<String, (u8, Instant)>>,
+}
+
+impl PiShockBackend {
+    pub fn new() -> Result<PiShockBackend, BackendError> {
+        log::debug!("PiShock Touchpoint Handler");
+        let config = config::get_config();
+        let pishock_config = &config.pishock;
+
+        if pishock_config.username.is_empty() || config.firmware.api_authtoken.is_empty() {
+            return Err(BackendError::Unauthorized("PiShock needs pishock.username and firmware.api_authtoken. Please check your config.toml file.".to_string()));
+        }
+        if pishock_config.share_codes.is_empty() {
+            log::warn!("No PiShock share codes configured, PiShock will not receive any commands");
+        }
+
+        Ok(PiShockBackend {
+            api: PiShockApi::new(&pishock_config.api_url, &pishock_config.username, &config.firmware.api_authtoken, &pishock_config.name)?,
+            share_codes: pishock_config.share_codes.clone(),
+            running: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn op(method: u8) -> Option<u8> {
+        match method {
+            METHOD_SHOCK => Some(api::OP_SHOCK),
+            METHOD_VIBRATE => Some(api::OP_VIBRATE),
+            METHOD_SOUND => Some(api::OP_BEEP),
+            _ => None,
+        }
+    }
+}
+
+// 0.0 - 1.0 to PiShock's 1 - 100, anything above zero is at least 1
+fn to_pishock_intensity(intensity: f32) -> u8 {
+    ((intensity.clamp(0.0, 1.0) * 100.0).round() as u8).max(api::MIN_INTENSITY)
+}
+
+// Milliseconds to whole seconds, rounded up so short touches still fire
+fn to_pishock_duration(duration_ms: u64) -> u8 {
+    let secs = duration_ms.div_ceil(1000).min(api::MAX_DURATION_SECS as u64) as u8;
+    secs.max(api::MIN_DURATION_SECS)
+}
+
+#[async_trait]
+impl ShockerBackend for PiShockBackend {
+    fn name(&self) -> &'static str {
+        "PiShock"
+    }
+
+    async fn send_control(&self, commands: &[ShockerCommand]) -> Result<Vec<Sent>, BackendError> {
+        let now = Instant::now();
+        let mut operations = Vec::new();
+        {
+            let mut running = self.running.lock().await;
+            for command in commands {
+                // IDs without a share code belong to another backend
+                let code = match self.share_codes.get(&command.id) {
+                    Some(code) => code,
+                    None => continue,
+                };
+                let op = match PiShockBackend::op(command.method) {
+                    Some(op) => op,
+                    None => {
+                        log::error!("PiShock has no operation for method {}", command.method);
+                        continue;
+                    }
+                };
+                let intensity = to_pishock_intensity(command.intensity);
+                let duration = to_pishock_duration(command.duration);
+
+                // Still running with the same op and intensity, let it finish
+                let running_key = format!("{}_{}", code, op);
+                if let Some(&(running_intensity, until)) = running.get(&running_key) {
+                    if running_intensity == intensity && until > now {
+                        continue;
+                    }
+                }
+                running.insert(running_key, (intensity, now + Duration::from_secs(duration as u64)));
+                operations.push((command, code.clone(), op, intensity, duration));
+            }
+        }
+
+        let results = join_all(operations.iter().map(|(_, code, op, intensity, duration)| {
+            log::debug!("PiShock operate: code {} op {} intensity {} duration {}s", code, op, intensity, duration);
+            self.api.operate(code, *op, *intensity, *duration)
+        })).await;
+
+        let mut sent = Vec::new();
+        let mut first_error = None;
+        for ((command, code, op, _, duration), result) in operations.iter().zip(results) {
+            match result {
+                Ok(()) => sent.push(Sent::new(&command.id, command.method, Duration::from_secs(*duration as u64))),
+                Err(e) => {
+                    // Not running, so the next pass can retry
+                    self.running.lock().await.remove(&format!("{}_{}", code, op));
+                    log::error!("PiShock share code {} failed: {}", code, e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        // Whatever did go out still has to be charged, the failures are logged above
+        match first_error {
+            Some(e) if sent.is_empty() => Err(e),
+            _ => Ok(sent),
+        }
+    }
+
+    fn hardware_duration(&self, command: &ShockerCommand) -> Option<Duration> {
+        self.share_codes.contains_key(&command.id).then(|| Duration::from_secs(to_pishock_duration(command.duration) as u64))
+    }
+
+    async fn stop_all(&self) -> Result<(), BackendError> {
+        self.running.lock().await.clear();
+        // PiShock has no stop operation and rejects intensity 0, the weakest vibrate it takes replaces
+        // whatever is running, so a shock ends right away and at most a 1 s buzz at 1% is left
+        let results = join_all(self.share_codes.values().map(|code| {
+            self.api.operate(code, api::OP_VIBRATE, api::MIN_INTENSITY, api::MIN_DURATION_SECS)
+        })).await;
+        results.into_iter().collect()
+    }
+
+    async fn stop(&self, ids: &[String]) -> Result<(), BackendError> {
+        let codes: Vec<&String> = ids.iter().filter_map(|id| self.share_codes.get(id)).collect();
+        if codes.is_empty() {
+            return Ok(());
+        }
+        self.running.lock().await
+            .retain(|running_key, _| !codes.iter().any(|code| running_key.rsplit_once('_').map(|(running_code, _)| running_code) == Some(code.as_str())));
+        // Same as stop_all, the weakest vibrate replaces whatever is running
+        let results = join_all(codes.iter().map(|code| {
+            self.api.operate(code, api::OP_VIBRATE, api::MIN_INTENSITY, api::MIN_DURATION_SECS)
+        })).await;
+        results.into_iter().collect()
+    }
+
+    async fn list_shockers(&self) -> Result<Vec<ShockerInfo>, BackendError> {
+        let mut shockers = Vec::new();
+        for (id, code) in &self.share_codes {
+            let info = self.api.shocker_info(code).await?;
+            shockers.push(ShockerInfo {
+                id: id.clone(),
+                name: info.name,
+                paused: info.paused,
+            });
+        }
+        Ok(shockers)
+    }
+
+    async fn health(&self) -> BackendHealth {
+        let code = match self.share_codes.values().next() {
+            Some(code) => code,
+            None => return BackendHealth::Disconnected("No PiShock share codes configured".to_string()),
+        };
+        match self.api.shocker_info(code).await {
+            Ok(info) if info.online => BackendHealth::Connected,
+            Ok(_) => BackendHealth::Disconnected("PiShock hub is offline".to_string()),
+            Err(e) => BackendHealth::Disconnected(e.to_string()),
+        }
+    }
+
+    // Every request is awaited in send_control, so nothing is left in flight
+    async fn close(&self) -> Result<(), BackendError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::test_server::TestServer;
+    use serde_json::Value;
+
+    fn backend(server: &TestServer) -> PiShockBackend {
+        PiShockBackend {
+            api: PiShockApi::new(&server.url(), "puppy", "test-key", "ShockRS").unwrap(),
+            share_codes: HashMap::from([("collar".to_string(), "17519CD8GAP".to_string())]),
+            running: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[tokio::test]
+    async fn short_shock_is_sent_as_a_whole_second() {
+        let mut server = TestServer::start(200, "Operation Succeeded.").await;
+        let backend = backend(&server);
+        let command = ShockerCommand { id: "collar".to_string(), method: METHOD_SHOCK, intensity: 0.25, duration: 50 };
+
+        assert_eq!(backend.hardware_duration(&command), Some(Duration::from_secs(1)));
+        let sent = backend.send_control(&[command]).await.unwrap();
+        assert_eq!(sent, vec![Sent::new("collar", METHOD_SHOCK, Duration::from_secs(1))]);
+
+        let request = server.request().await;
+        assert_eq!(request.path, "/apioperate");
+        let body: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["Code"], "17519CD8GAP");
+        assert_eq!(body["Op"], api::OP_SHOCK);
+        assert_eq!(body["Intensity"], 25);
+        assert_eq!(body["Duration"], 1);
+        assert_eq!(body["Username"], "puppy");
+        assert_eq!(body["Apikey"], "test-key");
+    }
+
+    // Intensity 0 is out of range for PiShock, the stop has to be something it accepts
+    #[tokio::test]
+    async fn stop_is_the_weakest_accepted_vibrate() {
+        let mut server = TestServer::start(200, "Operation Succeeded.").await;
+        backend(&server).stop_all().await.unwrap();
+
+        let body: Value = serde_json::from_str(&server.request().await.body).unwrap();
+        assert_eq!(body["Op"], api::OP_VIBRATE);
+        assert_eq!(body["Intensity"], 1);
+        assert_eq!(body["Duration"], 1);
+    }
+
+    #[tokio::test]
+    async fn error_responses_map_to_backend_errors() {
+        let command = ShockerCommand { id: "collar".to_string(), method: METHOD_VIBRATE, intensity: 0.5, duration: 1000 };
+
+        let server = TestServer::start(200, "Not Authorized.").await;
+        assert!(matches!(backend(&server).send_control(std::slice::from_ref(&command)).await, Err(BackendError::Unauthorized(_))));
+
+        let server = TestServer::start(200, "Device currently not connected.").await;
+        assert!(matches!(backend(&server).send_control(std::slice::from_ref(&command)).await, Err(BackendError::Connection(_))));
+
+        let server = TestServer::start(200, "Shocker is Paused or does not exist. Unpause to send command.").await;
+        assert!(matches!(backend(&server).send_control(std::slice::from_ref(&command)).await, Err(BackendError::Rejected(_))));
+
+        let server = TestServer::start(429, "").await;
+        assert!(matches!(backend(&server).send_control(&[command]).await, Err(BackendError::RateLimited(None))));
     }
-}
\ No newline at end of file
+}
@@ -1 +1,2 @@
+pub mod api;
 pub mod handler;
\ No newline at end of file
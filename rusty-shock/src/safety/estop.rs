@@ -0,0 +1,249 @@
+// Global emergency stop, once engaged nothing reaches a shocker until it is explicitly re-armed
+use crate::config;
+use crate::osc::touchpoints::CommandState;
+use once_cell::sync::Lazy;
+use rosc::{OscMessage, OscType};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex, Notify};
+use tokio::time::Instant;
+
+static LATCHED: AtomicBool = AtomicBool::new(false);
+static ENGAGED: Lazy<Notify> = Lazy::new(Notify::new);
+// Goes up on every engage, work scheduled for later compares it to know a stop happened in between
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+// Requests bigger than this aren't from our CLI
+const MAX_REQUEST_BYTES: usize = 8192;
+
+// Browsers can't send a custom header cross origin without a CORS preflight we never answer
+const TOKEN_HEADER: &str = "x-shockrs-token";
+
+pub fn engage(source: &str) {
+    EPOCH.fetch_add(1, Ordering::SeqCst);
+    if !LATCHED.swap(true, Ordering::SeqCst) {
+        log::warn!("EMERGENCY STOP engaged by {}. Re-arm to resume.", source);
+    }
+    // Wake the backend router right away instead of on its next pass
+    ENGAGED.notify_waiters();
+}
+
+pub fn rearm(source: &str) {
+    if LATCHED.swap(false, Ordering::SeqCst) {
+        log::warn!("Emergency stop re-armed by {}", source);
+    }
+}
+
+pub fn is_engaged() -> bool {
+    LATCHED.load(Ordering::SeqCst)
+}
+
+// Changes whenever the emergency stop is engaged, even if it was re-armed since
+pub fn epoch() -> u64 {
+    EPOCH.load(Ordering::SeqCst)
+}
+
+// Resolves when the emergency stop is engaged
+pub async fn engaged() {
+    ENGAGED.notified().await;
+}
+
+// Zero every command so nothing is left to resend
+pub async fn zero_commands(commandmap: &Arc<Mutex<HashMap<String, CommandState>>>) {
+    let now = Instant::now();
+    let mut command_states = commandmap.lock().await;
+    for command_state in command_states.values_mut() {
+        command_state.intensity = 0.0;
+        command_state.duration = 0;
+        command_state.expiry = now;
+    }
+}
+
+// Handle the configured emergency stop OSC addresses, returns true if the message was one of them
+// Checked before the parameter store so a quick press and release can't be coalesced away
+pub fn handle_osc(message: &OscMessage) -> bool {
+    let estop_config = &config::get_config().emergency_stop;
+
+    let is_stop = !estop_config.osc_address.is_empty() && message.addr == estop_config.osc_address;
+    let is_rearm = !estop_config.rearm_osc_address.is_empty() && message.addr == estop_config.rearm_osc_address;
+    if !is_stop && !is_rearm {
+        return false;
+    }
+
+    // Only the press counts, releasing the button does nothing
+    let pressed = match message.args.first() {
+        Some(OscType::Bool(value)) => *value,
+        Some(OscType::Int(value)) => *value != 0,
+        Some(OscType::Float(value)) => *value >= 0.5,
+        None => true,
+        Some(other) => {
+            log::warn!("Ignoring emergency stop OSC message with argument {:?}", other);
+            false
+        }
+    };
+    if pressed {
+        if is_stop {
+            engage("OSC");
+        } else {
+            rearm("OSC");
+        }
+    }
+    true
+}
+
+// Local control endpoint, only listens on loopback
+//   POST /estop   engage
+//   POST /arm     re-arm
+//   GET  /status  current state
+// Every request needs the X-ShockRS-Token header (matching emergency_stop.control_token when set) and no Origin,
+// so a web page open in the browser can't re-arm through it
+pub async fn start_control_server(port: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    log::info!("Emergency stop control endpoint listening on 127.0.0.1:{}", port);
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle_control_request(stream).await {
+                log::error!("Emergency stop control request from {} failed: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_control_request(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    // Only the request line matters, read until the end of the headers
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut lines = request.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+
+    let mut origin = false;
+    let mut token = None;
+    for line in lines.take_while(|line| !line.is_empty()) {
+        if let Some((name, value)) = line.split_once(':') {
+            match name.trim().to_ascii_lowercase().as_str() {
+                "origin" => origin = true,
+                TOKEN_HEADER => token = Some(value.trim()),
+                _ => {},
+            }
+        }
+    }
+    let control_token = &config::get_config().emergency_stop.control_token;
+    let authorized = !origin && token.is_some_and(|token| control_token.is_empty() || token == control_token);
+
+    let status = match (method, path) {
+        _ if !authorized => {
+            log::warn!("Refused emergency stop control request {} {} (origin: {}, token: {})", method, path, origin, token.is_some());
+            "403 Forbidden"
+        },
+        ("POST", "/estop") => {
+            engage("control endpoint");
+            "200 OK"
+        },
+        ("POST", "/arm") => {
+            rearm("control endpoint");
+            "200 OK"
+        },
+        ("GET", "/status") => "200 OK",
+        (_, "/estop") | (_, "/arm") | (_, "/status") => "405 Method Not Allowed",
+        _ => "404 Not Found",
+    };
+
+    let body = format!("{{\"engaged\":{}}}", is_engaged());
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// rusty-shock estop | arm | status, talks to the control endpoint of the running instance
+pub async fn run_cli(command: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let port = config::get_config().emergency_stop.control_port;
+    if port == 0 {
+        return Err("The emergency stop control endpoint is disabled (emergency_stop.control_port = 0)".into());
+    }
+
+    let client = reqwest::Client::new();
+    let url = format!("http://127.0.0.1:{}", port);
+    let request = match command {
+        "estop" => client.post(format!("{}/estop", url)),
+        "arm" => client.post(format!("{}/arm", url)),
+        "status" => client.get(format!("{}/status", url)),
+        _ => return Err(format!("Unknown command {}, expected estop, arm or status", command).into()),
+    };
+
+    let response = request.header(TOKEN_HEADER, &config::get_config().emergency_stop.control_token).send().await
+        .map_err(|e| format!("Could not reach Rusty Shock on {}, is it running? ({})", url, e))?;
+    if response.status() == reqwest::StatusCode::FORBIDDEN {
+        return Err("Rusty Shock refused the request, check emergency_stop.control_token matches the running instance".into());
+    }
+    let status: serde_json::Value = serde_json::from_str(&response.text().await?)?;
+    let engaged = status["engaged"].as_bool().unwrap_or(false);
+    println!("Emergency stop is {}", if engaged { "ENGAGED" } else { "armed (not engaged)" });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Send one raw request through handle_control_request and return the status line
+    async fn control_request(request: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_control_request(stream).await.unwrap();
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        server.await.unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn browser_and_unmarked_requests_are_refused() {
+        let engaged = is_engaged();
+
+        // A page in the browser: simple POST with an Origin, no custom header
+        let status = control_request("POST /arm HTTP/1.1\r\nHost: 127.0.0.1\r\nOrigin: http://example.com\r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 403 Forbidden");
+        // The custom header doesn't help once an Origin is there
+        let status = control_request("POST /estop HTTP/1.1\r\nOrigin: null\r\nX-ShockRS-Token: \r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 403 Forbidden");
+        // No token header at all
+        let status = control_request("POST /estop HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 403 Forbidden");
+
+        assert_eq!(is_engaged(), engaged);
+    }
+
+    #[tokio::test]
+    async fn cli_request_is_answered() {
+        // config.toml leaves control_token blank, so only the header has to be there
+        let status = control_request("GET /status HTTP/1.1\r\nHost: 127.0.0.1\r\nX-ShockRS-Token: \r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let status = control_request("GET /nothing HTTP/1.1\r\nX-ShockRS-Token: \r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+    }
+}
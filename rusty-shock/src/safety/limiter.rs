@@ -0,0 +1,278 @@
+// Last check between the command map and the backends, whatever the touchpoints or World Command ask for
+// never goes past the limits in the [safety] section of config.toml
+// Budgets are charged with what the hardware actually runs, backends round short shocks up (PiShock 1 s, OpenShock 300 ms)
+use crate::backend::{Sent, ShockerCommand, METHOD_SHOCK, METHOD_VIBRATE};
+use crate::config::{self, Safety};
+use std::collections::{HashMap, VecDeque};
+use tokio::time::{Duration, Instant};
+
+const BUDGET_WINDOW: Duration = Duration::from_secs(60);
+
+pub struct Limiter {
+    limits: Safety,
+    // Shock time charged per send, for the rolling minute
+    window: VecDeque<(Instant, Duration)>,
+    session_total: Duration,
+    // When the last shock sent to each shocker ends on the hardware
+    shock_end: HashMap<String, Instant>,
+    // Last clamp/rejection logged per command map key, so a held touchpoint logs once instead of every pass
+    notices: HashMap<String, String>,
+}
+
+impl Limiter {
+    pub fn new() -> Limiter {
+        Limiter::with_limits(config::get_config().safety.clone())
+    }
+
+    pub fn with_limits(limits: Safety) -> Limiter {
+        log::info!(
+            "Safety limits: shock intensity {:.2}, vibrate intensity {:.2}, {} of shock per minute, {} per session, {} ms between shocks",
+            limits.max_shock_intensity,
+            limits.max_vibrate_intensity,
+            describe_budget(limits.shock_seconds_per_minute),
+            describe_budget(limits.shock_seconds_per_session),
+            limits.min_shock_gap_ms,
+        );
+
+        Limiter {
+            limits,
+            window: VecDeque::new(),
+            session_total: Duration::ZERO,
+            shock_end: HashMap::new(),
+            notices: HashMap::new(),
+        }
+    }
+
+    // Clamp or drop every command that breaks a limit, must be called once per dispatch pass
+    // hardware_duration is how long the backends would really run a command, after their own clamping and rounding
+    pub fn apply(&mut self, commands: Vec<ShockerCommand>, now: Instant, hardware_duration: impl Fn(&ShockerCommand) -> Duration) -> Vec<ShockerCommand> {
+        while self.window.front().is_some_and(|(charged_at, _)| now.saturating_duration_since(*charged_at) >= BUDGET_WINDOW) {
+            self.window.pop_front();
+        }
+
+        let mut notices: HashMap<String, String> = HashMap::new();
+        let mut allowed = Vec::with_capacity(commands.len());
+
+        // Shocks firing together this pass share what is left of the budget
+        let shocks = commands.iter().filter(|command| command.method == METHOD_SHOCK).count();
+        let share = match self.remaining_budget() {
+            Ok(remaining) => remaining.map(|remaining| remaining / shocks.max(1) as u32),
+            Err(reason) => {
+                for command in commands.iter().filter(|command| command.method == METHOD_SHOCK) {
+                    notices.insert(format!("{}_{}", command.id, command.method), format!("rejected, {}", reason));
+                }
+                Some(Duration::ZERO)
+            }
+        };
+
+        for mut command in commands {
+            let key = format!("{}_{}", command.id, command.method);
+
+            let max_intensity = match command.method {
+                METHOD_SHOCK => self.limits.max_shock_intensity,
+                METHOD_VIBRATE => self.limits.max_vibrate_intensity,
+                _ => 1.0,
+            };
+            if command.intensity > max_intensity {
+                notices.insert(key.clone(), format!(
+                    "intensity clamped from {:.2} to {:.2} (method {} limit)",
+                    command.intensity, max_intensity, command.method
+                ));
+                command.intensity = max_intensity;
+            }
+
+            if command.method == METHOD_SHOCK {
+                if let Err(reason) = self.check_gap(&command.id, now) {
+                    notices.insert(key, format!("rejected, {}", reason));
+                    continue;
+                }
+                if let Some(share) = share {
+                    if share.is_zero() {
+                        // Already noted above
+                        continue;
+                    }
+                    if hardware_duration(&command) > share {
+                        // Shorten it, unless the backend would round it straight back up
+                        let share_ms = share.as_millis() as u64;
+                        let clamped = ShockerCommand { duration: command.duration.min(share_ms), ..command.clone() };
+                        let hardware = hardware_duration(&clamped);
+                        if hardware > share {
+                            notices.insert(key, format!(
+                                "rejected, runs {} ms on the hardware and only {} ms of shock time budget is left for it",
+                                hardware.as_millis(), share_ms
+                            ));
+                            continue;
+                        }
+                        let notice = format!("duration clamped from {} ms to {} ms (shock time budget)", command.duration, clamped.duration);
+                        // Keep the intensity clamp from above if there was one
+                        notices.entry(key.clone())
+                            .and_modify(|notices| { notices.push_str(", "); notices.push_str(&notice); })
+                            .or_insert(notice);
+                        command = clamped;
+                    }
+                }
+            }
+
+            allowed.push(command);
+        }
+
+        for (key, notice) in &notices {
+            if self.notices.get(key) != Some(notice) {
+                log::warn!("Safety limiter: shocker {} {}", key, notice);
+            }
+        }
+        self.notices = notices;
+
+        allowed
+    }
+
+    // Charge what the backends put on the hardware, only the time a send adds past what was already running counts
+    pub fn record(&mut self, sent: &[Sent], now: Instant) {
+        for sent in sent.iter().filter(|sent| sent.method == METHOD_SHOCK) {
+            let end = self.shock_end.entry(sent.id.clone()).or_insert(now);
+            let new_end = now + sent.duration;
+            if new_end > *end {
+                let charged = new_end - (*end).max(now);
+                *end = new_end;
+                self.window.push_back((now, charged));
+                self.session_total += charged;
+            }
+        }
+    }
+
+    // Nothing is being sent and the backends were told to stop (EX: emergency stop), running shocks end now
+    pub fn idle(&mut self, now: Instant) {
+        for end in self.shock_end.values_mut() {
+            if *end > now {
+                *end = now;
+            }
+        }
+    }
+
+    // A new shock has to wait min_shock_gap_ms after the last one ended on the hardware
+    // Commands for a shock that is still running are a continuation, not a new shock
+    fn check_gap(&self, id: &str, now: Instant) -> Result<(), String> {
+        if self.limits.min_shock_gap_ms == 0 {
+            return Ok(());
+        }
+        let end = match self.shock_end.get(id) {
+            Some(end) if *end <= now => *end,
+            _ => return Ok(()),
+        };
+        let gap = Duration::from_millis(self.limits.min_shock_gap_ms);
+        let since = now - end;
+        if since < gap {
+            return Err(format!("{} ms since the last shock ended, minimum gap is {} ms", since.as_millis(), gap.as_millis()));
+        }
+        Ok(())
+    }
+
+    // Ok with the shock time left (None when unlimited), Err with the reason no shock is allowed
+    fn remaining_budget(&self) -> Result<Option<Duration>, String> {
+        let mut remaining: Option<Duration> = None;
+
+        if self.limits.shock_seconds_per_minute > 0.0 {
+            let budget = Duration::from_secs_f32(self.limits.shock_seconds_per_minute);
+            let used: Duration = self.window.iter().map(|(_, charged)| *charged).sum();
+            if used >= budget {
+                return Err(format!("{:.1}s of shock in the last minute, limit is {:.1}s", used.as_secs_f32(), budget.as_secs_f32()));
+            }
+            remaining = Some(budget - used);
+        }
+
+        if self.limits.shock_seconds_per_session > 0.0 {
+            let budget = Duration::from_secs_f32(self.limits.shock_seconds_per_session);
+            if self.session_total >= budget {
+                return Err(format!("{:.1}s of shock this session, limit is {:.1}s", self.session_total.as_secs_f32(), budget.as_secs_f32()));
+            }
+            let session_remaining = budget - self.session_total;
+            remaining = Some(remaining.map_or(session_remaining, |remaining| remaining.min(session_remaining)));
+        }
+
+        Ok(remaining)
+    }
+}
+
+fn describe_budget(seconds: f32) -> String {
+    if seconds > 0.0 {
+        format!("{:.1}s", seconds)
+    } else {
+        "unlimited".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shock(id: &str, duration: u64) -> ShockerCommand {
+        ShockerCommand { id: id.to_string(), method: METHOD_SHOCK, intensity: 1.0, duration }
+    }
+
+    fn sent(command: &ShockerCommand, duration: Duration) -> Sent {
+        Sent { id: command.id.clone(), method: command.method, duration }
+    }
+
+    // PiShock style, whole seconds rounded up
+    fn whole_seconds(command: &ShockerCommand) -> Duration {
+        Duration::from_secs(command.duration.div_ceil(1000).max(1))
+    }
+
+    #[test]
+    fn budget_is_charged_with_the_rounded_duration() {
+        let mut limiter = Limiter::with_limits(Safety { shock_seconds_per_minute: 2.5, ..Default::default() });
+        let now = Instant::now();
+
+        // A 50 ms touch is a full second on PiShock
+        let allowed = limiter.apply(vec![shock("a", 50)], now, whole_seconds);
+        assert_eq!(allowed.len(), 1);
+        limiter.record(&[sent(&allowed[0], whole_seconds(&allowed[0]))], now);
+
+        let now = now + Duration::from_secs(1);
+        let allowed = limiter.apply(vec![shock("a", 50)], now, whole_seconds);
+        limiter.record(&[sent(&allowed[0], whole_seconds(&allowed[0]))], now);
+
+        // 0.5 s left, clamping to 500 ms would still be rounded up to a second, so it is dropped
+        let now = now + Duration::from_secs(1);
+        assert!(limiter.apply(vec![shock("a", 50)], now, whole_seconds).is_empty());
+    }
+
+    #[test]
+    fn resending_a_running_shock_only_charges_the_extension() {
+        let mut limiter = Limiter::with_limits(Safety { shock_seconds_per_minute: 1.0, ..Default::default() });
+        let now = Instant::now();
+        let command = shock("a", 300);
+
+        // Streamed every 150 ms with a 300 ms hardware duration, 450 ms on the hardware over two sends
+        limiter.record(&[sent(&command, Duration::from_millis(300))], now);
+        limiter.record(&[sent(&command, Duration::from_millis(300))], now + Duration::from_millis(150));
+        assert_eq!(limiter.session_total, Duration::from_millis(450));
+    }
+
+    #[test]
+    fn shocks_in_the_same_pass_split_the_budget() {
+        let mut limiter = Limiter::with_limits(Safety { shock_seconds_per_minute: 1.0, ..Default::default() });
+        let exact = |command: &ShockerCommand| Duration::from_millis(command.duration);
+
+        let allowed = limiter.apply(vec![shock("a", 1000), shock("b", 1000)], Instant::now(), exact);
+        assert_eq!(allowed.len(), 2);
+        assert!(allowed.iter().all(|command| command.duration == 500));
+    }
+
+    #[test]
+    fn gap_starts_when_the_hardware_shock_ends() {
+        let mut limiter = Limiter::with_limits(Safety { min_shock_gap_ms: 500, ..Default::default() });
+        let exact = |command: &ShockerCommand| Duration::from_millis(command.duration);
+        let now = Instant::now();
+
+        let allowed = limiter.apply(vec![shock("a", 50)], now, exact);
+        // The backend ran it for 1 s
+        limiter.record(&[sent(&allowed[0], Duration::from_secs(1))], now);
+
+        // Still running, so a resend is a continuation
+        assert_eq!(limiter.apply(vec![shock("a", 50)], now + Duration::from_millis(500), exact).len(), 1);
+        // Ended at 1 s, 300 ms later is inside the gap
+        assert!(limiter.apply(vec![shock("a", 50)], now + Duration::from_millis(1300), exact).is_empty());
+        assert_eq!(limiter.apply(vec![shock("a", 50)], now + Duration::from_millis(1500), exact).len(), 1);
+    }
+}
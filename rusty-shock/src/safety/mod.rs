@@ -0,0 +1,2 @@
+pub mod estop;
+pub mod limiter;
@@ -0,0 +1,200 @@
+use async_shutdown::ShutdownManager;
+use crate::backend::ShockerBackend;
+use crate::osc::touchpoints::CommandState;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
+
+// The reason is only used for logging
+pub type Shutdown = ShutdownManager<&'static str>;
+
+// Tasks that are stopped together, main stops the stages one after another so nothing upstream
+// is still writing while the next stage winds down
+pub struct Stage {
+    name: &'static str,
+    shutdown: Shutdown,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Stage {
+    pub fn new(name: &'static str) -> Stage {
+        Stage { name, shutdown: Shutdown::new(), tasks: Vec::new() }
+    }
+
+    // Dropped wherever it is awaiting once the stage stops
+    pub fn spawn<F: Future<Output = ()> + Send + 'static>(&mut self, task: F) {
+        let task = self.shutdown.wrap_cancel(task);
+        self.tasks.push(tokio::spawn(async move {
+            let _ = task.await;
+        }));
+    }
+
+    // For tasks that watch the stage's shutdown themselves and return when they are done
+    pub fn spawn_graceful<F: Future<Output = ()> + Send + 'static>(&mut self, task: F) {
+        self.tasks.push(tokio::spawn(task));
+    }
+
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    pub async fn stop(self, reason: &'static str) {
+        let _ = self.shutdown.trigger_shutdown(reason);
+        for task in self.tasks {
+            if let Err(e) = task.await {
+                log::error!("Task failed during shutdown: {}", e);
+            }
+        }
+        log::debug!("Shutdown: {} stopped", self.name);
+    }
+}
+
+// A backend that doesn't answer in time shouldn't keep the process alive
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Trigger the shutdown on Ctrl+C, or SIGTERM on unix
+pub async fn listen_for_signals(shutdown: Shutdown) {
+    let reason = wait_for_signal().await;
+    log::info!("Received {}, shutting down", reason);
+    // A second signal while already shutting down is fine to ignore
+    let _ = shutdown.trigger_shutdown(reason);
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            log::error!("Failed to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return "Ctrl+C";
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "Ctrl+C",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> &'static str {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        log::error!("Failed to listen for Ctrl+C: {}", e);
+    }
+    "Ctrl+C"
+}
+
+// Last thing before exit: forget every command, stop every shocker and close the connections
+pub async fn stop_all_shockers(commandmap: &Arc<Mutex<HashMap<String, CommandState>>>, backends: &[Arc<dyn ShockerBackend>]) {
+    commandmap.lock().await.clear();
+    log::debug!("Command map cleared");
+
+    for backend in backends {
+        match time::timeout(STOP_TIMEOUT, backend.stop_all()).await {
+            Ok(Ok(())) => log::info!("{} backend stopped all shockers", backend.name()),
+            Ok(Err(e)) => log::error!("{} backend failed to stop all shockers: {}", backend.name(), e),
+            Err(_) => log::error!("{} backend timed out stopping all shockers", backend.name()),
+        }
+
+        match time::timeout(STOP_TIMEOUT, backend.close()).await {
+            Ok(Ok(())) => log::debug!("{} backend closed", backend.name()),
+            Ok(Err(e)) => log::error!("{} backend failed to close cleanly: {}", backend.name(), e),
+            Err(_) => log::error!("{} backend timed out closing", backend.name()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{BackendError, BackendHealth, Sent, ShockerCommand, ShockerInfo};
+    use async_trait::async_trait;
+    use std::sync::Mutex as StdMutex;
+
+    // Writes down what it was asked to do, in order
+    struct Recorder {
+        calls: Arc<StdMutex<Vec<&'static str>>>,
+    }
+
+    #[async_trait]
+    impl ShockerBackend for Recorder {
+        fn name(&self) -> &'static str {
+            "Recorder"
+        }
+        async fn send_control(&self, _commands: &[ShockerCommand]) -> Result<Vec<Sent>, BackendError> {
+            self.calls.lock().unwrap().push("send_control");
+            Ok(Vec::new())
+        }
+        fn hardware_duration(&self, _command: &ShockerCommand) -> Option<Duration> {
+            None
+        }
+        async fn stop_all(&self) -> Result<(), BackendError> {
+            self.calls.lock().unwrap().push("stop_all");
+            Ok(())
+        }
+        async fn stop(&self, _ids: &[String]) -> Result<(), BackendError> {
+            self.calls.lock().unwrap().push("stop");
+            Ok(())
+        }
+        async fn list_shockers(&self) -> Result<Vec<ShockerInfo>, BackendError> {
+            Ok(Vec::new())
+        }
+        async fn health(&self) -> BackendHealth {
+            BackendHealth::Connected
+        }
+        async fn close(&self) -> Result<(), BackendError> {
+            self.calls.lock().unwrap().push("close");
+            Ok(())
+        }
+    }
+
+    // Each stage is fully stopped before the next one starts, graceful tasks get to finish their work
+    #[tokio::test]
+    async fn stages_stop_in_order() {
+        let order = Arc::new(StdMutex::new(Vec::new()));
+
+        let mut first = Stage::new("first");
+        first.spawn(std::future::pending());
+        let mut second = Stage::new("second");
+        let second_shutdown = second.shutdown();
+        let second_order = Arc::clone(&order);
+        second.spawn_graceful(async move {
+            second_shutdown.wait_shutdown_triggered().await;
+            time::sleep(Duration::from_millis(20)).await;
+            second_order.lock().unwrap().push("second");
+        });
+
+        first.stop("test").await;
+        order.lock().unwrap().push("first");
+        second.stop("test").await;
+        order.lock().unwrap().push("done");
+
+        assert_eq!(*order.lock().unwrap(), vec!["first", "second", "done"]);
+    }
+
+    #[tokio::test]
+    async fn backends_are_stopped_then_closed() {
+        let calls = Arc::new(StdMutex::new(Vec::new()));
+        let backends: Vec<Arc<dyn ShockerBackend>> = vec![Arc::new(Recorder { calls: Arc::clone(&calls) })];
+        let commandmap = Arc::new(Mutex::new(HashMap::new()));
+        let now = tokio::time::Instant::now();
+        commandmap.lock().await.insert("1234_1".to_string(), CommandState {
+            id: "1234".to_string(),
+            duration: 50,
+            intensity: 0.5,
+            last_issued: now,
+            expiry: now + Duration::from_secs(1),
+        });
+
+        stop_all_shockers(&commandmap, &backends).await;
+
+        assert!(commandmap.lock().await.is_empty());
+        assert_eq!(*calls.lock().unwrap(), vec!["stop_all", "close"]);
+    }
+}
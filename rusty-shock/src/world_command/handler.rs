@@ -1,52 +1,67 @@
-use notify::{RecursiveMode, Watcher};
-use std::sync::mpsc as std_mpsc; // Use the standard library's MPSC for notify
 use tokio::sync::mpsc;
+use tokio::time::{self, Duration};
 use crate::WorldCommandEvent;
-use std::env;
+use crate::config;
+use crate::world_command::logwatch::{self, LogTailer};
+use crate::world_command::rules;
 use std::path::PathBuf;
 
-pub async fn start_world_command_server(mut tx: mpsc::Sender<WorldCommandEvent>) -> Result<(), Box<dyn std::error::Error>> {
+// notify can miss appends on some platforms (VRChat doesn't always flush in a way that fires an event)
+// so the log is also checked on this interval
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub async fn start_world_command_server(tx: mpsc::Sender<WorldCommandEvent>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     log::debug!("Starting WorldCommand server");
-    
-    // Set up the non-async std::sync::mpsc channel for notify
-    let (notify_tx, notify_rx) = std_mpsc::channel::<notify::Result<notify::Event>>();
-
-    // Set up the watcher
-        // Automatically select the best implementation for your platform.
-        let mut watcher = notify::recommended_watcher(|res| {
-            match res {
-               Ok(event) => println!("event: {:?}", event),
-               Err(e) => println!("watch error: {:?}", e),
+
+    let rules = &*rules::RULES;
+    if rules.is_empty() {
+        log::warn!("No World Command rules in worldcommand.toml, the VRChat log will be followed but nothing will trigger");
+    }
+
+    let log_directory = log_directory()?;
+    log::info!("VRChat log directory: {}", log_directory.display());
+
+    // Set up the watcher, it only tells us which log changed
+    let (log_update_tx, mut log_update_rx) = mpsc::channel::<PathBuf>(64);
+    let _watcher = logwatch::start_watching_fs(log_directory.clone(), log_update_tx)?;
+
+    let mut tailer = LogTailer::new(log_directory)?;
+    let mut poll_timer = time::interval(POLL_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = poll_timer.tick() => {},
+            Some(path) = log_update_rx.recv() => {
+                log::trace!("VRChat log changed: {}", path.display());
+            },
+        }
+
+        let lines = match tailer.poll() {
+            Ok(lines) => lines,
+            Err(e) => {
+                // The log can briefly vanish while VRChat rotates it, try again next tick
+                log::error!("Failed to read VRChat log: {}", e);
+                continue;
             }
-        })?;
-
-    // Specify the path to watch
-    //let path_to_watch = r"%appdata%\..\LocalLow\VRChat\VRChat\"; // This should be the directory where the files are located
-    let appdata_var = env::var("APPDATA").map_err(|_| "Could not find APPDATA environment variable")?;
-    let vrchat_path_buf = PathBuf::from(appdata_var).join(r"..\LocalLow\VRChat\VRChat\");
-    let vrchat_path = vrchat_path_buf.as_path(); // Convert PathBuf to &Path
-    watcher.watch(vrchat_path, RecursiveMode::Recursive)?;
-
-
-    
-    // This task will run on a separate thread as it's a blocking operation
-    tokio::spawn(async move {
-        loop {
-            match notify_rx.recv() {
-                Ok(event) => {
-                    println!("Received filesystem event: {:?}", event);
-                    // Here you would implement your logic to handle the event,
-                    // such as reading the latest file, parsing the event, and creating a WorldCommandEvent.
-                    // You'll then send this event through the tx channel.
-                    
-                    // Example event handling:
-                    // let world_event = parse_event_to_world_command(event);
-                    // tx.send(world_event).await.expect("Failed to send world command event");
-                },
-                Err(e) => println!("watch error: {:?}", e),
+        };
+        for line in lines {
+            log::trace!("VRChat log: {}", line);
+            for event in rules::match_line(rules, &line) {
+                log::info!("World Command matched: {:?}", event);
+                // The router is gone, nothing left to do
+                tx.send(event).await?;
             }
         }
-    });
+    }
+}
+
+// world_command.log_directory from config.toml, or wherever VRChat keeps its logs on this platform
+fn log_directory() -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    let configured = &config::get_config().world_command.log_directory;
+    if !configured.trim().is_empty() {
+        return Ok(PathBuf::from(configured.trim()));
+    }
 
-    Ok(())
+    logwatch::default_log_directory()
+        .ok_or_else(|| "Could not find the VRChat log directory, set log_directory under [world_command] in config.toml".into())
 }
@@ -1,39 +1,267 @@
-use notify::{Watcher, RecursiveMode, Event, EventKind};
-use std::path::PathBuf;
-use std::sync::mpsc as std_mpsc;
+use notify::{Watcher, RecursiveMode, RecommendedWatcher, Event, EventKind};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use tokio::sync::mpsc as async_mpsc;
 
+// VRChat's Steam app ID, the Proton prefix lives under compatdata/<app id>
+const VRCHAT_APP_ID: &str = "438100";
+
+// Watch the log directory and forward the changed log paths to the tailer
+// The returned watcher must be kept alive, dropping it stops the events
 pub fn start_watching_fs(
     log_directory: PathBuf,
     log_update_tx: async_mpsc::Sender<PathBuf>,
-) -> notify::Result<()> {
-    let (notify_tx, notify_rx) = std_mpsc::channel::<notify::Result<notify::Event>>();
-        // Set up the watcher
-        // Automatically select the best implementation for your platform.
-        let mut watcher = notify::recommended_watcher(|res| {
-            match res {
-               Ok(event) => println!("event: {:?}", event),
-               Err(e) => println!("watch error: {:?}", e),
-            }
-        })?;
+) -> notify::Result<RecommendedWatcher> {
+    // The handler runs on notify's own thread, so it must never block
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+        match res {
+            Ok(Event { kind: EventKind::Create(_), paths, .. }) |
+            Ok(Event { kind: EventKind::Modify(_), paths, .. }) => {
+                for path in paths.into_iter().filter(|path| is_output_log(path)) {
+                    // A full channel just means the tailer already has work queued
+                    let _ = log_update_tx.try_send(path);
+                }
+            },
+            Err(e) => log::error!("VRChat log watch error: {:?}", e),
+            _ => {}
+        }
+    })?;
     watcher.watch(&log_directory, RecursiveMode::NonRecursive)?;
 
-    // The thread where notify runs
-    std::thread::spawn(move || {
-        for event in notify_rx.iter() {
-            match event {
-                Ok(Event { kind: EventKind::Create(_), paths, .. }) | 
-                Ok(Event { kind: EventKind::Modify(_), paths, .. }) => {
-                    for path in paths {
-                        // You may want to add some logic here to verify it's a log file, and if it's newer than the current one.
-                        log_update_tx.blocking_send(path).expect("Failed to send update log path");
-                    }
-                },
-                Err(e) => eprintln!("watch error: {:?}", e),
-                _ => {}
+    Ok(watcher)
+}
+
+// VRChat writes one output_log_<date>_<time>.txt per launch
+fn is_output_log(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with("output_log_") && name.ends_with(".txt"))
+        .unwrap_or(false)
+}
+
+// The most recently modified output log, the file name sorts by launch time so it breaks ties
+pub fn newest_log(log_directory: &Path) -> io::Result<Option<PathBuf>> {
+    let mut newest: Option<(std::time::SystemTime, PathBuf)> = None;
+
+    for entry in fs::read_dir(log_directory)? {
+        let path = entry?.path();
+        if !is_output_log(&path) {
+            continue;
+        }
+        let modified = match fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(_) => continue,
+        };
+        let is_newer = match &newest {
+            Some((newest_modified, newest_path)) => (modified, &path) > (*newest_modified, newest_path),
+            None => true,
+        };
+        if is_newer {
+            newest = Some((modified, path));
+        }
+    }
+
+    Ok(newest.map(|(_, path)| path))
+}
+
+// Where VRChat keeps its logs on this machine
+pub fn default_log_directory() -> Option<PathBuf> {
+    if cfg!(target_os = "windows") {
+        // %APPDATA% is ...\AppData\Roaming, the logs are in ...\AppData\LocalLow
+        let appdata = env::var_os("APPDATA")?;
+        let log_directory = PathBuf::from(appdata).parent()?.join("LocalLow").join("VRChat").join("VRChat");
+        return log_directory.is_dir().then_some(log_directory);
+    }
+
+    // Linux, VRChat runs under Proton inside the Steam library it was installed to
+    let home = PathBuf::from(env::var_os("HOME")?);
+    let steam_roots = [
+        home.join(".steam").join("steam"),
+        home.join(".steam").join("root"),
+        home.join(".local").join("share").join("Steam"),
+        home.join(".var").join("app").join("com.valvesoftware.Steam").join(".local").join("share").join("Steam"),
+    ];
+
+    let mut libraries: Vec<PathBuf> = Vec::new();
+    for steam_root in steam_roots.iter().filter(|path| path.is_dir()) {
+        libraries.push(steam_root.clone());
+        libraries.extend(steam_library_folders(steam_root));
+    }
+
+    libraries.into_iter()
+        .map(|library| library
+            .join("steamapps").join("compatdata").join(VRCHAT_APP_ID)
+            .join("pfx").join("drive_c").join("users").join("steamuser")
+            .join("AppData").join("LocalLow").join("VRChat").join("VRChat"))
+        .find(|path| path.is_dir())
+}
+
+// Extra Steam libraries from steamapps/libraryfolders.vdf, lines look like: "path"    "/mnt/games/SteamLibrary"
+fn steam_library_folders(steam_root: &Path) -> Vec<PathBuf> {
+    let vdf = match fs::read_to_string(steam_root.join("steamapps").join("libraryfolders.vdf")) {
+        Ok(vdf) => vdf,
+        Err(_) => return Vec::new(),
+    };
+
+    vdf.lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with("\"path\""))
+        .filter_map(|line| line.rsplit('"').nth(1))
+        .map(PathBuf::from)
+        .collect()
+}
+
+// Follows the newest output log, switching to the next one when VRChat starts a new log
+pub struct LogTailer {
+    log_directory: PathBuf,
+    current: Option<PathBuf>,
+    offset: u64,
+    // Bytes after the last newline, VRChat can be mid-write when we read
+    // Kept as bytes so a UTF-8 character split across two reads isn't mangled
+    partial: Vec<u8>,
+}
+
+impl LogTailer {
+    // Starts at the end of the newest log, lines written before we started are not replayed
+    pub fn new(log_directory: PathBuf) -> io::Result<LogTailer> {
+        let current = newest_log(&log_directory)?;
+        let offset = match &current {
+            Some(path) => fs::metadata(path)?.len(),
+            None => 0,
+        };
+        match &current {
+            Some(path) => log::info!("Following VRChat log: {}", path.display()),
+            None => log::info!("No VRChat log yet in {}, waiting for one", log_directory.display()),
+        }
+
+        Ok(LogTailer {
+            log_directory,
+            current,
+            offset,
+            partial: Vec::new(),
+        })
+    }
+
+    // Complete lines appended since the last poll
+    pub fn poll(&mut self) -> io::Result<Vec<String>> {
+        let mut lines = Vec::new();
+        let newest = newest_log(&self.log_directory)?;
+
+        if newest.is_some() && newest != self.current {
+            // Finish the old log before moving on, its last lines may not have been read yet
+            if self.current.is_some() {
+                lines.extend(self.read_appended()?);
+                // Nothing more is coming for the old log, so whatever is left is a whole line
+                let rest = std::mem::take(&mut self.partial);
+                if !rest.is_empty() {
+                    lines.push(decode_line(&rest));
+                }
+            }
+            if let Some(path) = &newest {
+                log::info!("VRChat log rotated, following: {}", path.display());
             }
+            // A new log was created after we started, so read it from the beginning
+            self.current = newest;
+            self.offset = 0;
+        }
+
+        lines.extend(self.read_appended()?);
+        Ok(lines)
+    }
+
+    fn read_appended(&mut self) -> io::Result<Vec<String>> {
+        let path = match &self.current {
+            Some(path) => path,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut file = File::open(path)?;
+        let length = file.metadata()?.len();
+        if length < self.offset {
+            // Truncated underneath us, start over
+            log::warn!("VRChat log {} was truncated, reading from the start", path.display());
+            self.offset = 0;
+            self.partial.clear();
+        }
+        if length == self.offset {
+            return Ok(Vec::new());
         }
-    });
 
-    Ok(())
-}
\ No newline at end of file
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut buffer = Vec::new();
+        let read = file.read_to_end(&mut buffer)?;
+        self.offset += read as u64;
+
+        self.partial.extend_from_slice(&buffer);
+        // Whatever follows the last newline is still being written
+        let complete = match self.partial.iter().rposition(|byte| *byte == b'\n') {
+            Some(end) => self.partial.drain(..=end).collect::<Vec<u8>>(),
+            None => return Ok(Vec::new()),
+        };
+
+        Ok(complete.split(|byte| *byte == b'\n')
+            .map(decode_line)
+            .filter(|line| !line.trim().is_empty())
+            .collect())
+    }
+}
+
+// Only ever called with whole lines, so invalid UTF-8 here really is in the log
+fn decode_line(line: &[u8]) -> String {
+    String::from_utf8_lossy(line).trim_end_matches('\r').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    // Fresh directory per test under the system temp dir
+    fn log_directory(name: &str) -> PathBuf {
+        let directory = env::temp_dir().join(format!("rusty-shock-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn append(path: &Path, bytes: &[u8]) {
+        fs::OpenOptions::new().create(true).append(true).open(path).unwrap().write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn character_split_across_reads_is_kept_whole() {
+        let directory = log_directory("split-utf8");
+        let log = directory.join("output_log_2024-01-01_00-00-00.txt");
+        append(&log, b"before we started\n");
+        let mut tailer = LogTailer::new(directory.clone()).unwrap();
+
+        let line = "2024.01.01 00:00:00 Debug      -  [ShockRS] caf\u{e9} \u{1f43e}\r\n".as_bytes();
+        // Cut inside the 4 byte paw print
+        let cut = line.len() - 4;
+        append(&log, &line[..cut]);
+        assert!(tailer.poll().unwrap().is_empty());
+        append(&log, &line[cut..]);
+        assert_eq!(tailer.poll().unwrap(), vec!["2024.01.01 00:00:00 Debug      -  [ShockRS] caf\u{e9} \u{1f43e}".to_string()]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn rotation_finishes_the_old_log() {
+        let directory = log_directory("rotation");
+        let old_log = directory.join("output_log_2024-01-01_00-00-00.txt");
+        append(&old_log, b"");
+        let mut tailer = LogTailer::new(directory.clone()).unwrap();
+
+        append(&old_log, b"first\nlast without newline");
+        assert_eq!(tailer.poll().unwrap(), vec!["first".to_string()]);
+
+        // Sorts after the old one, so it is the newest even with the same modified time
+        append(&directory.join("output_log_2024-01-02_00-00-00.txt"), b"new log\n");
+        assert_eq!(tailer.poll().unwrap(), vec!["last without newline".to_string(), "new log".to_string()]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,2 +1,3 @@
 pub mod handler;
-pub mod logwatch;
\ No newline at end of file
+pub mod logwatch;
+pub mod rules;
\ No newline at end of file
@@ -0,0 +1,228 @@
+use crate::WorldCommandEvent;
+use crate::backend::{METHOD_SHOCK, METHOD_VIBRATE, METHOD_SOUND};
+use crate::osc::touchpoints;
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+
+// Fields of WorldCommandEvent a capture group can fill
+const EVENT_FIELDS: [&str; 4] = ["address", "method", "intensity", "duration"];
+
+#[derive(Deserialize)]
+pub struct WorldCommands {
+    #[serde(default)]
+    pub worldcmd: Vec<RuleConfig>,
+}
+
+// One [[worldcmd]] entry as written in worldcommand.toml
+#[derive(Deserialize)]
+pub struct RuleConfig {
+    pub pattern: String,
+    // WorldCommandEvent field -> capture group name
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    pub destination: String,
+    #[serde(default = "default_intensity")]
+    pub intensity: f32,
+    // Empty means every method the destination touchpoint allows
+    #[serde(default)]
+    pub method: Vec<u8>,
+    // None means the destination touchpoint's duration
+    pub duration: Option<u64>,
+}
+
+fn default_intensity() -> f32 {
+    1.0
+}
+
+pub struct Rule {
+    regex: Regex,
+    config: RuleConfig,
+}
+
+pub static RULES: Lazy<Vec<Rule>> = Lazy::new(|| {
+    let rules_path = "worldcommand.toml";
+    let rules_str = match fs::read_to_string(rules_path) {
+        Ok(rules_str) => rules_str,
+        Err(e) => {
+            log::warn!("Failed to read {}: {}. No World Command rules loaded.", rules_path, e);
+            return Vec::new();
+        }
+    };
+    let world_commands: WorldCommands = match toml::from_str(&rules_str) {
+        Ok(world_commands) => world_commands,
+        Err(e) => {
+            log::error!("Failed to parse {}: {}. No World Command rules loaded.", rules_path, e);
+            return Vec::new();
+        }
+    };
+
+    let rules = compile_rules(world_commands.worldcmd);
+    log::info!("Loaded {} World Command rule(s)", rules.len());
+    rules
+});
+
+// Compile every rule, a broken rule is logged and skipped so the rest still work
+pub fn compile_rules(configs: Vec<RuleConfig>) -> Vec<Rule> {
+    configs.into_iter().filter_map(|config| {
+        let regex = match Regex::new(&config.pattern) {
+            Ok(regex) => regex,
+            Err(e) => {
+                log::error!("Invalid World Command pattern {}: {}", config.pattern, e);
+                return None;
+            }
+        };
+        for (field, group) in &config.variables {
+            if !EVENT_FIELDS.contains(&field.as_str()) {
+                log::warn!("World Command rule {} maps unknown field {}, expected one of {:?}", config.pattern, field, EVENT_FIELDS);
+            }
+            if !regex.capture_names().flatten().any(|name| name == group) {
+                log::warn!("World Command rule {} has no capture group named {}", config.pattern, group);
+            }
+        }
+        Some(Rule { regex, config })
+    }).collect()
+}
+
+// Every event the line triggers, one per matching rule
+pub fn match_line(rules: &[Rule], line: &str) -> Vec<WorldCommandEvent> {
+    rules.iter().filter_map(|rule| {
+        let captures = rule.regex.captures(line)?;
+        let event = rule.to_event(&captures);
+        if event.is_none() {
+            log::warn!("World Command rule {} matched but produced no usable event: {}", rule.config.pattern, line);
+        }
+        event
+    }).collect()
+}
+
+impl Rule {
+    // A capture for an event field, through variables first, then a group named after the field
+    fn field<'a>(&self, captures: &'a Captures, field: &str) -> Option<&'a str> {
+        let group = self.config.variables.get(field).map(String::as_str).unwrap_or(field);
+        captures.name(group).map(|capture| capture.as_str().trim())
+    }
+
+    fn to_event(&self, captures: &Captures) -> Option<WorldCommandEvent> {
+        // destination can pull in captures with $name or ${name}
+        let address = match self.field(captures, "address") {
+            Some(address) => address.to_string(),
+            None => {
+                let mut address = String::new();
+                captures.expand(&self.config.destination, &mut address);
+                address
+            }
+        };
+        if address.is_empty() {
+            return None;
+        }
+        // Anyone in the world can get text into the log, only touchpoints from touchpoints.toml can be targeted
+        if !touchpoints::get_config().all_devices().any(|device| device.address == address) {
+            log::warn!("World Command rule {} targets {}, which is not a configured touchpoint", self.config.pattern, address);
+            return None;
+        }
+
+        // A captured intensity is weighted by the rule intensity
+        let intensity = match self.field(captures, "intensity") {
+            Some(captured) => match parse_intensity(captured) {
+                Some(captured) => captured * self.config.intensity,
+                None => {
+                    log::warn!("World Command rule {} captured intensity {}, expected 0.0 - 1.0 or 0% - 100%", self.config.pattern, captured);
+                    return None;
+                }
+            },
+            None => self.config.intensity,
+        };
+
+        let method = match self.field(captures, "method") {
+            Some(captured) => vec![parse_method(captured)?],
+            None => self.config.method.clone(),
+        };
+
+        // Without a duration the touchpoint's own maximum applies, the router caps it there anyway
+        let duration = match self.field(captures, "duration") {
+            Some(captured) => captured.parse::<u64>().ok()?,
+            None => self.config.duration.unwrap_or(u64::MAX),
+        };
+
+        Some(WorldCommandEvent {
+            address,
+            method,
+            intensity: intensity.clamp(0.0, 1.0),
+            duration,
+        })
+    }
+}
+
+// One scale: a fraction from 0.0 to 1.0, or a percentage when it ends in %, anything outside is refused
+fn parse_intensity(intensity: &str) -> Option<f32> {
+    let (value, scale) = match intensity.strip_suffix('%') {
+        Some(percent) => (percent.trim(), 100.0),
+        None => (intensity, 1.0),
+    };
+    let value = value.parse::<f32>().ok()? / scale;
+    (0.0..=1.0).contains(&value).then_some(value)
+}
+
+// Worlds can log the method by number or by name
+fn parse_method(method: &str) -> Option<u8> {
+    match method.to_ascii_lowercase().as_str() {
+        "shock" => Some(METHOD_SHOCK),
+        "vibrate" | "vibe" => Some(METHOD_VIBRATE),
+        "sound" | "beep" => Some(METHOD_SOUND),
+        other => other.parse::<u8>().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example rule from worldcommand.toml
+    fn example_rules() -> Vec<Rule> {
+        let world_commands: WorldCommands = toml::from_str(&fs::read_to_string("worldcommand.toml").unwrap()).unwrap();
+        compile_rules(world_commands.worldcmd)
+    }
+
+    #[test]
+    fn udon_debug_line_triggers() {
+        let rules = example_rules();
+        // touchpoints.toml has a "tail" touchpoint
+        let events = match_line(&rules, "2024.05.01 20:15:42 Debug      -  [ShockRS] tail 0.5");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].address, "tail");
+        assert!((events[0].intensity - 0.5).abs() < 1e-6);
+
+        let events = match_line(&rules, "2024.05.01 20:15:42 Debug      -  [ShockRS] tail 25%");
+        assert!((events[0].intensity - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn text_elsewhere_in_the_log_does_not_trigger() {
+        let rules = example_rules();
+        // Other log levels and lines where the tag isn't at the start of the message, EX: a player name or chat relay
+        assert!(match_line(&rules, "2024.05.01 20:15:42 Warning    -  [ShockRS] tail 1").is_empty());
+        assert!(match_line(&rules, "2024.05.01 20:15:42 Debug      -  [Behaviour] OnPlayerJoined [ShockRS] tail 1").is_empty());
+        assert!(match_line(&rules, "2024.05.01 20:15:42 Debug      -  [ShockRS] tail 1 and then some").is_empty());
+    }
+
+    #[test]
+    fn only_configured_touchpoints_can_be_targeted() {
+        let rules = example_rules();
+        assert!(match_line(&rules, "2024.05.01 20:15:42 Debug      -  [ShockRS] nothere 0.5").is_empty());
+    }
+
+    #[test]
+    fn intensity_has_one_scale() {
+        assert_eq!(parse_intensity("1"), Some(1.0));
+        assert_eq!(parse_intensity("0.02"), Some(0.02));
+        assert_eq!(parse_intensity("2%"), Some(0.02));
+        assert_eq!(parse_intensity("100%"), Some(1.0));
+        // Not 2%, and not clamped up to 100% either
+        assert_eq!(parse_intensity("2"), None);
+        assert_eq!(parse_intensity("150%"), None);
+        assert_eq!(parse_intensity("-1"), None);
+    }
+}